@@ -0,0 +1,374 @@
+use bevy::{
+    input::{
+        mouse::{MouseScrollUnit, MouseWheel},
+        InputSystem,
+    },
+    prelude::*,
+    utils::HashSet,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs};
+
+use crate::ron_file::save_ron;
+
+/// Adds the input bindings and `ActionInput`, which is filled by
+/// `DeviceInputPlugin` or by the app itself.
+#[derive(Default)]
+pub struct ActionsPlugin {
+    /// Where the bindings are loaded from, and saved to when they are changed.
+    /// `None` uses the default bindings and keeps changes in memory only.
+    pub save_path: Option<String>,
+}
+
+impl Plugin for ActionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputBindings::load(self.save_path.clone()))
+            .init_resource::<ActionInput>();
+    }
+}
+
+/// Fills `ActionInput` from the keyboard, mouse and gamepads every frame.
+pub struct DeviceInputPlugin;
+
+impl Plugin for DeviceInputPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(CoreStage::PreUpdate, update_action_input.after(InputSystem));
+    }
+}
+
+pub const BINDINGS_PATH: &str = "bindings.ron";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    TurnLeft,
+    TurnRight,
+    Fire,
+    RaiseElevation,
+    LowerElevation,
+    SelectStandardAmmo,
+    SelectHighExplosiveAmmo,
+    SelectArmorPiercingAmmo,
+    SelectBouncingAmmo,
+    Restart,
+    Pause,
+    OpenControls,
+    SlowDownTime,
+    SpeedUpTime,
+    FreezeTime,
+    StepTime,
+    ToggleFreeCamera,
+    FreeCameraForward,
+    FreeCameraBack,
+    FreeCameraLeft,
+    FreeCameraRight,
+    FreeCameraUp,
+    FreeCameraDown,
+    /// Held while moving the mouse to turn the free camera.
+    TurnFreeCamera,
+    ToggleBulletPoolStats,
+}
+
+impl Action {
+    pub const ALL: [Action; 27] = [
+        Action::MoveForward,
+        Action::MoveBack,
+        Action::TurnLeft,
+        Action::TurnRight,
+        Action::Fire,
+        Action::RaiseElevation,
+        Action::LowerElevation,
+        Action::SelectStandardAmmo,
+        Action::SelectHighExplosiveAmmo,
+        Action::SelectArmorPiercingAmmo,
+        Action::SelectBouncingAmmo,
+        Action::Restart,
+        Action::Pause,
+        Action::OpenControls,
+        Action::SlowDownTime,
+        Action::SpeedUpTime,
+        Action::FreezeTime,
+        Action::StepTime,
+        Action::ToggleFreeCamera,
+        Action::FreeCameraForward,
+        Action::FreeCameraBack,
+        Action::FreeCameraLeft,
+        Action::FreeCameraRight,
+        Action::FreeCameraUp,
+        Action::FreeCameraDown,
+        Action::TurnFreeCamera,
+        Action::ToggleBulletPoolStats,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveForward => "Move Forward",
+            Action::MoveBack => "Move Back",
+            Action::TurnLeft => "Turn Left",
+            Action::TurnRight => "Turn Right",
+            Action::Fire => "Fire",
+            Action::RaiseElevation => "Raise Cannon",
+            Action::LowerElevation => "Lower Cannon",
+            Action::SelectStandardAmmo => "Standard Ammo",
+            Action::SelectHighExplosiveAmmo => "HE Ammo",
+            Action::SelectArmorPiercingAmmo => "AP Ammo",
+            Action::SelectBouncingAmmo => "Bouncing Ammo",
+            Action::Restart => "Restart",
+            Action::Pause => "Pause",
+            Action::OpenControls => "Controls",
+            Action::SlowDownTime => "Slow Down Time",
+            Action::SpeedUpTime => "Speed Up Time",
+            Action::FreezeTime => "Freeze Time",
+            Action::StepTime => "Step Frozen Time",
+            Action::ToggleFreeCamera => "Free Camera",
+            Action::FreeCameraForward => "Camera Forward",
+            Action::FreeCameraBack => "Camera Back",
+            Action::FreeCameraLeft => "Camera Left",
+            Action::FreeCameraRight => "Camera Right",
+            Action::FreeCameraUp => "Camera Up",
+            Action::FreeCameraDown => "Camera Down",
+            Action::TurnFreeCamera => "Turn Camera",
+            Action::ToggleBulletPoolStats => "Bullet Pool Stats",
+        }
+    }
+
+    fn default_binding(self) -> Binding {
+        match self {
+            Action::MoveForward => Binding::Key(KeyCode::W),
+            Action::MoveBack => Binding::Key(KeyCode::S),
+            Action::TurnLeft => Binding::Key(KeyCode::A),
+            Action::TurnRight => Binding::Key(KeyCode::D),
+            Action::Fire => Binding::Mouse(MouseButton::Left),
+            Action::RaiseElevation => Binding::WheelUp,
+            Action::LowerElevation => Binding::WheelDown,
+            Action::SelectStandardAmmo => Binding::Key(KeyCode::Key1),
+            Action::SelectHighExplosiveAmmo => Binding::Key(KeyCode::Key2),
+            Action::SelectArmorPiercingAmmo => Binding::Key(KeyCode::Key3),
+            Action::SelectBouncingAmmo => Binding::Key(KeyCode::Key4),
+            Action::Restart => Binding::Key(KeyCode::R),
+            Action::Pause => Binding::Key(KeyCode::Escape),
+            Action::OpenControls => Binding::Key(KeyCode::F1),
+            Action::SlowDownTime => Binding::Key(KeyCode::Minus),
+            Action::SpeedUpTime => Binding::Key(KeyCode::Equals),
+            Action::FreezeTime => Binding::Key(KeyCode::F5),
+            Action::StepTime => Binding::Key(KeyCode::F6),
+            Action::ToggleFreeCamera => Binding::Key(KeyCode::F2),
+            Action::FreeCameraForward => Binding::Key(KeyCode::Up),
+            Action::FreeCameraBack => Binding::Key(KeyCode::Down),
+            Action::FreeCameraLeft => Binding::Key(KeyCode::Left),
+            Action::FreeCameraRight => Binding::Key(KeyCode::Right),
+            Action::FreeCameraUp => Binding::Key(KeyCode::PageUp),
+            Action::FreeCameraDown => Binding::Key(KeyCode::PageDown),
+            Action::TurnFreeCamera => Binding::Mouse(MouseButton::Right),
+            Action::ToggleBulletPoolStats => Binding::Key(KeyCode::F3),
+        }
+    }
+
+    /// Gamepad buttons are not rebindable, movement and aiming use the sticks instead.
+    fn gamepad_button(self) -> Option<GamepadButtonType> {
+        match self {
+            Action::Fire => Some(GamepadButtonType::RightTrigger2),
+            Action::RaiseElevation => Some(GamepadButtonType::DPadUp),
+            Action::LowerElevation => Some(GamepadButtonType::DPadDown),
+            Action::Restart => Some(GamepadButtonType::Select),
+            Action::Pause => Some(GamepadButtonType::Start),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Just pressed, but never held, for every line scrolled.
+    WheelUp,
+    WheelDown,
+}
+
+impl Binding {
+    pub fn name(self) -> String {
+        match self {
+            Binding::Key(key) => format!("{:?}", key),
+            Binding::Mouse(MouseButton::Left) => "Left Mouse".to_string(),
+            Binding::Mouse(MouseButton::Right) => "Right Mouse".to_string(),
+            Binding::Mouse(MouseButton::Middle) => "Middle Mouse".to_string(),
+            Binding::Mouse(MouseButton::Other(button)) => format!("Mouse {}", button),
+            Binding::WheelUp => "Wheel Up".to_string(),
+            Binding::WheelDown => "Wheel Down".to_string(),
+        }
+    }
+}
+
+/// Which key or mouse button triggers each action. Persisted to the
+/// `save_path` of `ActionsPlugin`, if it has one.
+pub struct InputBindings {
+    bindings: BTreeMap<Action, Binding>,
+    save_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBindings(BTreeMap<Action, Binding>);
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        Self {
+            bindings: Action::ALL
+                .iter()
+                .map(|action| (*action, action.default_binding()))
+                .collect(),
+            save_path: None,
+        }
+    }
+}
+
+impl InputBindings {
+    /// Loads the bindings file, falling back to the default binding of any
+    /// action that is missing from it.
+    fn load(save_path: Option<String>) -> Self {
+        let mut bindings = Self::default();
+
+        if let Some(path) = &save_path {
+            match fs::read_to_string(path) {
+                Ok(content) => match ron::from_str::<SavedBindings>(&content) {
+                    Ok(saved) => bindings.bindings.extend(saved.0),
+                    Err(err) => warn!("Ignoring invalid {}: {}", path, err),
+                },
+                Err(_) => info!("No {} found, using default bindings", path),
+            }
+        }
+
+        Self {
+            save_path,
+            ..bindings
+        }
+    }
+
+    pub fn save(&self) {
+        if let Some(path) = &self.save_path {
+            save_ron(path, &SavedBindings(self.bindings.clone()));
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.bindings = Self::default().bindings;
+    }
+
+    pub fn get(&self, action: Action) -> Binding {
+        self.bindings[&action]
+    }
+
+    /// Binds `binding` to `action`. If another action already had it, that
+    /// action gets the old binding of `action` instead, so no two actions share one.
+    pub fn set(&mut self, action: Action, binding: Binding) {
+        let previous = self.get(action);
+        let taken_by = self
+            .bindings
+            .iter()
+            .find(|(other, other_binding)| **other != action && **other_binding == binding)
+            .map(|(other, _)| *other);
+
+        if let Some(other) = taken_by {
+            self.bindings.insert(other, previous);
+        }
+        self.bindings.insert(action, binding);
+    }
+}
+
+/// State of every action for the current frame. Gameplay systems read this
+/// instead of `Input<KeyCode>` or `Input<MouseButton>`.
+#[derive(Default)]
+pub struct ActionInput {
+    pressed: HashSet<Action>,
+    just_pressed: HashSet<Action>,
+}
+
+impl ActionInput {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed.contains(&action)
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.just_pressed.contains(&action)
+    }
+
+    /// Presses the action as if its key was just pressed. Meant for driving the
+    /// game without `DeviceInputPlugin`, which would overwrite this every frame.
+    pub fn press(&mut self, action: Action) {
+        self.pressed.insert(action);
+        self.just_pressed.insert(action);
+    }
+
+    pub fn release(&mut self, action: Action) {
+        self.pressed.remove(&action);
+        self.just_pressed.remove(&action);
+    }
+
+    pub fn clear_just_pressed(&mut self, action: Action) {
+        self.just_pressed.remove(&action);
+    }
+}
+
+/// Scrolling by pixels, e.g. on a touchpad, counts this many pixels as a line.
+const PIXELS_PER_SCROLL_LINE: f32 = 100.0;
+
+/// Lines scrolled by the mouse wheel during this frame, in whole lines. Pixels
+/// that do not add up to a line yet are kept in `partial_lines` for later frames.
+fn scrolled_lines(mouse_wheel: &mut EventReader<MouseWheel>, partial_lines: &mut f32) -> f32 {
+    *partial_lines += mouse_wheel
+        .iter()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / PIXELS_PER_SCROLL_LINE,
+        })
+        .sum::<f32>();
+
+    let lines = partial_lines.trunc();
+    *partial_lines -= lines;
+    lines
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_action_input(
+    bindings: Res<InputBindings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    mut actions: ResMut<ActionInput>,
+    mut partial_lines: Local<f32>,
+) {
+    actions.pressed.clear();
+    actions.just_pressed.clear();
+
+    let lines = scrolled_lines(&mut mouse_wheel, &mut partial_lines);
+
+    Action::ALL.iter().for_each(|action| {
+        let (mut pressed, mut just_pressed) = match bindings.get(*action) {
+            Binding::Key(key) => (
+                keyboard_input.pressed(key),
+                keyboard_input.just_pressed(key),
+            ),
+            Binding::Mouse(button) => (mouse.pressed(button), mouse.just_pressed(button)),
+            Binding::WheelUp => (false, lines > 0.0),
+            Binding::WheelDown => (false, lines < 0.0),
+        };
+
+        if let Some(button_type) = action.gamepad_button() {
+            gamepads.iter().for_each(|gamepad| {
+                let button = GamepadButton(*gamepad, button_type);
+                pressed |= gamepad_buttons.pressed(button);
+                just_pressed |= gamepad_buttons.just_pressed(button);
+            });
+        }
+
+        if pressed {
+            actions.pressed.insert(*action);
+        }
+        if just_pressed {
+            actions.just_pressed.insert(*action);
+        }
+    });
+}
@@ -1,34 +1,166 @@
-use crate::{game_layer::GameLayer, player::PlayerHit};
+use crate::{
+    game_layer::GameLayer,
+    game_state::GameState,
+    player::{PlayerHit, SHOOT_COOLDOWN},
+    simulation::{SimulationStage, SimulationTime},
+    tower::TowerSystem,
+};
 use bevy::prelude::*;
-use heron::prelude::*;
+use heron::{
+    prelude::*,
+    rapier_plugin::{PhysicsWorld, ShapeCastCollisionType},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 
 pub struct BulletPlugin;
 
 impl Plugin for BulletPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_bullet)
-            .add_system(move_bullets)
-            .add_system(auto_despawn_bullets)
-            .add_system(handle_bullets_collisions);
+        app.add_event::<BulletHit>()
+            .init_resource::<BulletPool>()
+            .add_startup_system(setup_bullet)
+            // bullets outlive the level in the pool, so they are not `LevelEntity`s
+            .add_system_set(
+                SystemSet::on_enter(GameState::Loading).with_system(release_all_bullets),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameState::MainMenu).with_system(release_all_bullets),
+            )
+            .add_system_set_to_stage(
+                SimulationStage,
+                // runs last, so the player reads its hits on the next tick
+                SystemSet::on_update(GameState::Playing)
+                    .after(TowerSystem::Simulate)
+                    .with_system(move_bullets.label(BulletSystem::Move))
+                    .with_system(
+                        handle_bullet_hits
+                            .label(BulletSystem::Hit)
+                            .after(BulletSystem::Move),
+                    )
+                    .with_system(auto_despawn_bullets.after(BulletSystem::Hit)),
+            );
     }
 }
 
-const BULLET_SIZE_RADIUS: f32 = 1.0;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum BulletSystem {
+    Move,
+    Hit,
+}
+
+pub const BULLET_SIZE_RADIUS: f32 = 1.0;
 const EXPLODE_RADIUS: f32 = 30.0;
-const EXPLODE_POWER: f32 = 25.0; // at zero range, this is the power
+const EXPLODE_IMPULSE: f32 = 2_400_000.0; // at zero range, throws a tower cube at 25 m/s
+/// Share of the speed that a bouncing bullet keeps on every bounce.
+const BOUNCE_RESTITUTION: f32 = 0.8;
+/// Gap left between a bouncing bullet and the surface it bounced off, so that
+/// it does not count as touching it on the next tick.
+const BOUNCE_CLEARANCE: f32 = 0.01;
 
-const BULLET_SPEED: f32 = 12.0;
+pub const BULLET_SPEED: f32 = 12.0;
+/// Speed of the player's shells, fast enough to lob them across most of the arena.
+pub const SHELL_SPEED: f32 = 24.0;
 const BULLET_LIFE: f32 = 30.0; // in case it goes out of range
 
 pub struct BulletAssets {
-    mesh: Handle<Mesh>,
     enemy_material: Handle<StandardMaterial>,
-    player_material: Handle<StandardMaterial>,
+    /// Indexed by `AmmoKind`.
+    ammo: [AmmoAssets; AmmoKind::ALL.len()],
+}
+
+struct AmmoAssets {
+    mesh: Handle<Mesh>,
+    material: Handle<StandardMaterial>,
 }
 
 #[derive(Component)]
 struct Move {
     velocity: Vec3,
+    ballistic: bool,
+}
+
+/// How many more times a bullet bounces off the ground and walls before it
+/// explodes on them.
+#[derive(Component)]
+struct Bounces(u32);
+
+/// Bullets that are not flying are kept around, hidden, and fired again
+/// instead of spawning a new entity for every shot.
+#[derive(Default)]
+pub struct BulletPool {
+    /// Ordered, so that the same bullet is handed out again on every run.
+    free: BTreeSet<Entity>,
+    size: usize,
+    requests: usize,
+    reuses: usize,
+}
+
+/// How well `BulletPool` keeps up with the bullets being fired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulletPoolStats {
+    pub size: usize,
+    pub free: usize,
+    pub requests: usize,
+    pub reuses: usize,
+}
+
+impl BulletPoolStats {
+    pub fn hit_rate(&self) -> f32 {
+        if self.requests == 0 {
+            0.0
+        } else {
+            self.reuses as f32 / self.requests as f32
+        }
+    }
+}
+
+impl BulletPool {
+    pub fn stats(&self) -> BulletPoolStats {
+        BulletPoolStats {
+            size: self.size,
+            free: self.free.len(),
+            requests: self.requests,
+            reuses: self.reuses,
+        }
+    }
+
+    fn acquire(&mut self, commands: &mut Commands) -> Entity {
+        self.requests += 1;
+
+        match self.free.iter().next().copied() {
+            Some(entity) => {
+                self.free.remove(&entity);
+                self.reuses += 1;
+                entity
+            }
+            None => {
+                self.size += 1;
+                commands.spawn_bundle(PbrBundle::default()).id()
+            }
+        }
+    }
+
+    /// Hides the bullet and stops it from flying, until it is fired again.
+    fn release(&mut self, commands: &mut Commands, entity: Entity) {
+        // e.g. it hit something on the same tick that its time ran out
+        if !self.free.insert(entity) {
+            return;
+        }
+
+        commands
+            .entity(entity)
+            .remove_bundle::<(Move, AutoDespawn, Bounces, BulletType, AmmoKind)>()
+            .insert(Visibility { is_visible: false });
+    }
+}
+
+/// A bullet ran into `other` while moving during this tick.
+struct BulletHit {
+    bullet: Entity,
+    other: Entity,
+    /// Surface normal of `other` where the bullet touched it.
+    normal: Vec3,
 }
 
 #[derive(Component)]
@@ -36,67 +168,201 @@ struct AutoDespawn {
     time_left: f32,
 }
 
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BulletType {
     Tower,
     Player,
 }
 
+/// What a bullet is loaded with. Towers always fire `Standard` bullets.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AmmoKind {
+    Standard,
+    HighExplosive,
+    ArmorPiercing,
+    Bouncing,
+}
+
+impl Default for AmmoKind {
+    fn default() -> Self {
+        AmmoKind::Standard
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmmoStats {
+    /// Multiplies the speed of bullets and shells.
+    pub speed_factor: f32,
+    pub cooldown: f32,
+    pub radius: f32,
+    pub explode_radius: f32,
+    /// Impulse at zero range, falling off to nothing at `explode_radius`.
+    pub explode_impulse: f32,
+    pub bounces: u32,
+}
+
+impl AmmoKind {
+    pub const ALL: [AmmoKind; 4] = [
+        AmmoKind::Standard,
+        AmmoKind::HighExplosive,
+        AmmoKind::ArmorPiercing,
+        AmmoKind::Bouncing,
+    ];
+
+    pub fn stats(self) -> AmmoStats {
+        match self {
+            AmmoKind::Standard => AmmoStats {
+                speed_factor: 1.0,
+                cooldown: SHOOT_COOLDOWN,
+                radius: BULLET_SIZE_RADIUS,
+                explode_radius: EXPLODE_RADIUS,
+                explode_impulse: EXPLODE_IMPULSE,
+                bounces: 0,
+            },
+            AmmoKind::HighExplosive => AmmoStats {
+                speed_factor: 0.8,
+                cooldown: 5.0,
+                radius: 1.4,
+                explode_radius: 45.0,
+                explode_impulse: 3_360_000.0,
+                bounces: 0,
+            },
+            AmmoKind::ArmorPiercing => AmmoStats {
+                speed_factor: 2.0,
+                cooldown: 4.0,
+                radius: 0.6,
+                explode_radius: 8.0,
+                explode_impulse: 2_880_000.0,
+                bounces: 0,
+            },
+            AmmoKind::Bouncing => AmmoStats {
+                speed_factor: 1.0,
+                cooldown: SHOOT_COOLDOWN,
+                radius: 0.8,
+                explode_radius: EXPLODE_RADIUS,
+                explode_impulse: 1_920_000.0,
+                bounces: 3,
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AmmoKind::Standard => "Standard",
+            AmmoKind::HighExplosive => "HE",
+            AmmoKind::ArmorPiercing => "AP",
+            AmmoKind::Bouncing => "Bouncing",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            AmmoKind::Standard => Color::BLUE,
+            AmmoKind::HighExplosive => Color::rgb(1.0, 0.5, 0.0),
+            AmmoKind::ArmorPiercing => Color::rgb(0.6, 0.6, 0.7),
+            AmmoKind::Bouncing => Color::GREEN,
+        }
+    }
+}
+
 fn setup_bullet(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    let mesh = meshes.add(
-        shape::Icosphere {
-            radius: BULLET_SIZE_RADIUS,
-            subdivisions: 8,
-        }
-        .into(),
-    );
     let enemy_material = materials.add(Color::RED.into());
-    let player_material = materials.add(Color::BLUE.into());
+    let ammo = AmmoKind::ALL.map(|kind| AmmoAssets {
+        mesh: meshes.add(
+            shape::Icosphere {
+                radius: kind.stats().radius,
+                subdivisions: 8,
+            }
+            .into(),
+        ),
+        material: materials.add(kind.color().into()),
+    });
 
     commands.insert_resource(BulletAssets {
-        mesh,
         enemy_material,
-        player_material,
+        ammo,
     });
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn spawn_bullet(
     commands: &mut Commands,
     assets: &Res<BulletAssets>,
+    pool: &mut BulletPool,
     position: Vec3,
     direction: Vec3,
+    speed: f32,
+    bullet_type: BulletType,
+    ammo: AmmoKind,
+) {
+    let mover = Move {
+        velocity: direction.normalize() * speed,
+        ballistic: false,
+    };
+    spawn_projectile(commands, assets, pool, position, mover, bullet_type, ammo);
+}
+
+pub fn spawn_shell(
+    commands: &mut Commands,
+    assets: &Res<BulletAssets>,
+    pool: &mut BulletPool,
+    position: Vec3,
+    velocity: Vec3,
     bullet_type: BulletType,
+    ammo: AmmoKind,
 ) {
-    let direction = direction.normalize();
+    let mover = Move {
+        velocity,
+        ballistic: true,
+    };
+    spawn_projectile(commands, assets, pool, position, mover, bullet_type, ammo);
+}
 
-    let mut entity = commands.spawn();
+fn spawn_projectile(
+    commands: &mut Commands,
+    assets: &Res<BulletAssets>,
+    pool: &mut BulletPool,
+    position: Vec3,
+    mover: Move,
+    bullet_type: BulletType,
+    ammo: AmmoKind,
+) {
+    let stats = ammo.stats();
+    let ammo_assets = &assets.ammo[ammo as usize];
+    let id = pool.acquire(commands);
+    let mut entity = commands.entity(id);
 
+    // everything is inserted again, since a bullet from the pool may have been
+    // fired by someone else with other ammo
     entity
-        .insert_bundle(PbrBundle {
-            mesh: assets.mesh.clone(),
-            material: match bullet_type {
-                BulletType::Tower => assets.enemy_material.clone(),
-                BulletType::Player => assets.player_material.clone(),
-            },
-            transform: Transform {
-                translation: position,
-                ..Default::default()
-            },
-            ..Default::default()
+        .insert(ammo_assets.mesh.clone())
+        .insert(match bullet_type {
+            BulletType::Tower => assets.enemy_material.clone(),
+            BulletType::Player => ammo_assets.material.clone(),
         })
-        .insert(RigidBody::Sensor)
+        .insert(Transform::from_translation(position))
+        // transforms are only propagated before the simulation, so this keeps a reused
+        // bullet from being drawn where it was last hit for a frame
+        .insert(GlobalTransform::from_translation(position))
+        .insert(Visibility { is_visible: true })
+        // not a body in the physics world, it is swept along its path instead
         .insert(CollisionShape::Sphere {
-            radius: BULLET_SIZE_RADIUS,
-        })
-        .insert(Move {
-            velocity: direction,
+            radius: stats.radius,
         })
+        .insert(mover)
         .insert(AutoDespawn {
             time_left: BULLET_LIFE,
-        });
+        })
+        .insert(bullet_type)
+        .insert(ammo);
+
+    if stats.bounces > 0 {
+        entity.insert(Bounces(stats.bounces));
+    }
 
     match bullet_type {
         BulletType::Tower => {
@@ -116,73 +382,245 @@ pub fn spawn_bullet(
     }
 }
 
-fn move_bullets(time: Res<Time>, mut query: Query<(&mut Transform, &Move)>) {
-    query.iter_mut().for_each(|(mut transform, mover)| {
-        transform.translation += mover.velocity * time.delta_seconds() * BULLET_SPEED;
-    });
+/// Where a shell launched from `position` with `velocity` comes down to the
+/// height `ground_y`, or `None` if it never does.
+pub fn shell_impact_point(
+    position: Vec3,
+    velocity: Vec3,
+    gravity: Vec3,
+    ground_y: f32,
+) -> Option<Vec3> {
+    // solves position.y + velocity.y * t + gravity.y * t^2 / 2 = ground_y for the latest t > 0
+    let a = gravity.y / 2.0;
+    let b = velocity.y;
+    let c = position.y - ground_y;
+
+    let time = if a.abs() < f32::EPSILON {
+        (b.abs() > f32::EPSILON).then(|| -c / b)?
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let root = discriminant.sqrt();
+        ((-b - root) / (2.0 * a)).max((-b + root) / (2.0 * a))
+    };
+
+    (time > 0.0).then(|| position + velocity * time + gravity * time * time / 2.0)
+}
+
+/// The angle above the horizon to launch a shell at, so that it lands `offset`
+/// away. Picks the high arc, which drops onto the target from above. `None` if
+/// the target is out of reach at this speed.
+pub fn shell_launch_angle(offset: Vec3, speed: f32, gravity: Vec3) -> Option<f32> {
+    let distance = Vec2::new(offset.x, offset.z).length();
+    let height = offset.y;
+    let g = -gravity.y;
+
+    if distance < f32::EPSILON || g <= 0.0 {
+        return None;
+    }
+
+    let speed_squared = speed * speed;
+    let discriminant = speed_squared * speed_squared
+        - g * (g * distance * distance + 2.0 * height * speed_squared);
+    if discriminant < 0.0 {
+        return None;
+    }
+
+    Some(((speed_squared + discriminant.sqrt()) / (g * distance)).atan())
+}
+
+/// Sweeps every bullet along the path it travels during this tick, so that
+/// fast bullets cannot pass through anything in between, and stops it at the
+/// first thing in the way.
+fn move_bullets(
+    time: Res<SimulationTime>,
+    gravity: Res<Gravity>,
+    physics_world: PhysicsWorld,
+    mut hits: EventWriter<BulletHit>,
+    mut query: Query<(
+        Entity,
+        &mut Transform,
+        &mut Move,
+        &CollisionShape,
+        &CollisionLayers,
+    )>,
+) {
+    let delta = time.delta_seconds();
+
+    query
+        .iter_mut()
+        .for_each(|(entity, mut transform, mut mover, shape, layers)| {
+            let travel = if mover.ballistic {
+                // exact for a constant gravity, so shells land where `shell_impact_point` says
+                let gravity = gravity.vector();
+                let travel = mover.velocity * delta + gravity * delta * delta / 2.0;
+                mover.velocity += gravity * delta;
+                travel
+            } else {
+                mover.velocity * delta
+            };
+
+            let hit = physics_world.shape_cast_with_filter(
+                shape,
+                transform.translation,
+                Quat::IDENTITY,
+                travel,
+                *layers,
+                |_| true,
+            );
+
+            match hit {
+                None => transform.translation += travel,
+                Some(hit) => {
+                    let normal = match hit.collision_type {
+                        ShapeCastCollisionType::Collided(info) => {
+                            transform.translation = info.self_end_position;
+                            // the normal on the bullet points at the surface, and the bullet
+                            // is never rotated, so unlike the other normal it is in world space
+                            -info.self_normal
+                        }
+                        // e.g. something moved into the bullet, which then stays where it is
+                        ShapeCastCollisionType::AlreadyPenetrating => -travel.normalize_or_zero(),
+                    };
+
+                    hits.send(BulletHit {
+                        bullet: entity,
+                        other: hit.entity,
+                        normal,
+                    });
+                }
+            }
+        });
 }
 
 fn auto_despawn_bullets(
-    time: Res<Time>,
+    time: Res<SimulationTime>,
     mut commands: Commands,
+    mut pool: ResMut<BulletPool>,
     mut query: Query<(Entity, &mut AutoDespawn)>,
 ) {
     query.iter_mut().for_each(|(entity, mut auto_despawn)| {
         auto_despawn.time_left -= time.delta_seconds();
 
         if auto_despawn.time_left <= 0.0 {
-            commands.entity(entity).despawn();
+            pool.release(&mut commands, entity);
         }
     });
 }
 
-fn handle_bullets_collisions(
-    mut events: EventReader<CollisionEvent>,
+fn release_all_bullets(
+    mut commands: Commands,
+    mut pool: ResMut<BulletPool>,
+    query: Query<Entity, With<BulletType>>,
+) {
+    query.iter().for_each(|entity| {
+        pool.release(&mut commands, entity);
+    });
+}
+
+fn handle_bullet_hits(
+    mut hits: EventReader<BulletHit>,
     mut player_hit: EventWriter<PlayerHit>,
     mut commands: Commands,
-    mut explode_query: Query<&mut Velocity>,
-    positions: Query<&GlobalTransform>,
+    mut pool: ResMut<BulletPool>,
+    mut bullets: Query<(
+        &mut Transform,
+        &mut Move,
+        &BulletType,
+        &AmmoKind,
+        Option<&mut Bounces>,
+    )>,
+    mut explode_query: Query<ExplodeTarget>,
+    layers: Query<&CollisionLayers>,
 ) {
-    events.iter().for_each(|event| {
-        if let CollisionEvent::Started(data1, data2) = event {
-            let datas = if data1.collision_layers().contains_group(GameLayer::Bullet) {
-                Some((data1, data2))
-            } else if data2.collision_layers().contains_group(GameLayer::Bullet) {
-                Some((data2, data1))
-            } else {
-                None
+    hits.iter().for_each(|hit| {
+        let (mut transform, mut mover, bullet_type, ammo, bounces) =
+            match bullets.get_mut(hit.bullet) {
+                Ok(bullet) => bullet,
+                Err(_) => return,
             };
+        let other_layers = layers.get(hit.other).ok().copied().unwrap_or_default();
 
-            if let Some((bullet, other)) = datas {
-                if other.collision_layers().contains_group(GameLayer::Player) {
-                    player_hit.send(PlayerHit);
-                }
-
-                let bullet_entity = bullet.rigid_body_entity();
-                let other_entity = other.rigid_body_entity();
-
-                let bullet_pos = positions.get(bullet_entity).unwrap();
-                let other_pos = positions.get(other_entity).unwrap();
+        if other_layers.contains_group(GameLayer::Player) {
+            player_hit.send(PlayerHit);
+        }
 
-                add_explode_force(
-                    &mut explode_query,
-                    &other_entity,
-                    other_pos.translation - bullet_pos.translation,
-                );
+        if other_layers.contains_group(GameLayer::World) {
+            if let Some(mut bounces) = bounces.filter(|bounces| bounces.0 > 0) {
+                bounces.0 -= 1;
+                let normal = hit.normal;
+                mover.velocity = (mover.velocity - 2.0 * mover.velocity.dot(normal) * normal)
+                    * BOUNCE_RESTITUTION;
+                transform.translation += normal * BOUNCE_CLEARANCE;
+                return;
+            }
+        }
 
-                commands.entity(bullet_entity).despawn();
+        let center = transform.translation;
+        let stats = ammo.stats();
+        match bullet_type {
+            BulletType::Player => explode_query.iter_mut().for_each(|target| {
+                add_explode_force(target, center, &stats);
+            }),
+            // only the body that was hit is pushed, a blast would knock over the
+            // tower that fired the bullet
+            BulletType::Tower => {
+                if let Ok(target) = explode_query.get_mut(hit.other) {
+                    add_explode_force(target, center, &stats);
+                }
             }
         }
+        pool.release(&mut commands, hit.bullet);
     });
 }
 
-fn add_explode_force(query: &mut Query<&mut Velocity>, entity: &Entity, direction: Vec3) {
-    if direction.length_squared() > EXPLODE_RADIUS * EXPLODE_RADIUS {
+type ExplodeTarget<'a> = (
+    &'a GlobalTransform,
+    &'a RigidBody,
+    &'a CollisionShape,
+    Option<&'a PhysicMaterial>,
+    &'a mut Velocity,
+);
+
+/// Pushes a dynamic body within the blast radius of `center` away from it,
+/// harder the closer and the lighter it is.
+fn add_explode_force(
+    (transform, body, shape, material, mut velocity): (
+        &GlobalTransform,
+        &RigidBody,
+        &CollisionShape,
+        Option<&PhysicMaterial>,
+        Mut<Velocity>,
+    ),
+    center: Vec3,
+    stats: &AmmoStats,
+) {
+    let radius = stats.explode_radius;
+    let offset = transform.translation - center;
+    if *body != RigidBody::Dynamic || offset.length_squared() > radius * radius {
         return;
     }
 
-    if let Ok(mut velocity) = query.get_mut(*entity) {
-        let power = ((EXPLODE_RADIUS - direction.length()) / EXPLODE_RADIUS) * EXPLODE_POWER;
-        velocity.linear += direction.normalize() * power;
+    // a body right on top of the blast is thrown upwards
+    let direction = if offset.length_squared() > f32::EPSILON {
+        offset.normalize()
+    } else {
+        Vec3::Y
+    };
+    let impulse = ((radius - offset.length()) / radius) * stats.explode_impulse;
+    let density = material.copied().unwrap_or_default().density;
+    velocity.linear += direction * impulse / (volume(shape) * density);
+}
+
+fn volume(shape: &CollisionShape) -> f32 {
+    match shape {
+        CollisionShape::Cuboid { half_extends, .. } => {
+            8.0 * half_extends.x * half_extends.y * half_extends.z
+        }
+        // no other shapes are given to dynamic bodies
+        _ => 1.0,
     }
 }
@@ -0,0 +1,134 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::{
+    game_state::{restart_level, GameState, LoadingAssets},
+    level::{CurrentLevel, Level},
+    ron_file::save_ron,
+};
+
+#[derive(Default)]
+pub struct CampaignPlugin {
+    /// Where the progress through the campaign is loaded from, and saved to
+    /// when a level is won. `None` starts with only the first level unlocked
+    /// and keeps the progress in memory only.
+    pub save_path: Option<String>,
+}
+
+impl Plugin for CampaignPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ProgressFile(self.save_path.clone()))
+            .add_startup_system(setup_campaign)
+            .add_system_set(SystemSet::on_enter(GameState::Victory).with_system(unlock_next_level));
+    }
+}
+
+pub const PROGRESS_PATH: &str = "progress.ron";
+
+/// The `save_path` of `CampaignPlugin`.
+struct ProgressFile(Option<String>);
+
+const CAMPAIGN_LEVELS: [&str; 3] = [
+    "levels/outpost.level.ron",
+    "levels/arena.level.ron",
+    "levels/fortress.level.ron",
+];
+
+pub struct Campaign {
+    pub levels: Vec<Handle<Level>>,
+    pub current: usize,
+    /// Number of levels, counting from the first one, that can be picked from the level select.
+    pub unlocked: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    unlocked: usize,
+}
+
+impl Progress {
+    fn load(file: &ProgressFile) -> Self {
+        let fresh = Self { unlocked: 1 };
+        let path = match &file.0 {
+            Some(path) => path,
+            None => return fresh,
+        };
+
+        match fs::read_to_string(path) {
+            Ok(content) => ron::from_str(&content).unwrap_or_else(|err| {
+                warn!("Ignoring invalid {}: {}", path, err);
+                fresh
+            }),
+            Err(_) => fresh,
+        }
+    }
+
+    fn save(&self, file: &ProgressFile) {
+        if let Some(path) = &file.0 {
+            save_ron(path, self);
+        }
+    }
+}
+
+impl Campaign {
+    pub fn is_unlocked(&self, index: usize) -> bool {
+        index < self.unlocked
+    }
+
+    pub fn has_next(&self) -> bool {
+        self.current + 1 < self.levels.len()
+    }
+}
+
+/// Makes `index` the current level of the campaign, and (re)starts it.
+pub fn play_level(
+    index: usize,
+    campaign: &mut Campaign,
+    current_level: &mut CurrentLevel,
+    state: &mut State<GameState>,
+) {
+    campaign.current = index;
+    current_level.0 = campaign.levels[index].clone();
+    restart_level(state);
+}
+
+fn setup_campaign(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    progress_file: Res<ProgressFile>,
+    mut loading: ResMut<LoadingAssets>,
+) {
+    let levels: Vec<Handle<Level>> = CAMPAIGN_LEVELS
+        .iter()
+        .map(|path| asset_server.load(*path))
+        .collect();
+
+    loading
+        .0
+        .extend(levels.iter().map(|level| level.clone_untyped()));
+
+    commands.insert_resource(CurrentLevel(levels[0].clone()));
+    // a campaign that got shorter still has its first level unlocked
+    let unlocked = Progress::load(&progress_file)
+        .unlocked
+        .clamp(1, levels.len());
+
+    commands.insert_resource(Campaign {
+        levels,
+        current: 0,
+        unlocked,
+    });
+}
+
+fn unlock_next_level(mut campaign: ResMut<Campaign>, progress_file: Res<ProgressFile>) {
+    let unlocked = campaign
+        .unlocked
+        .max(campaign.current + 2)
+        .min(campaign.levels.len());
+
+    if unlocked != campaign.unlocked {
+        campaign.unlocked = unlocked;
+        Progress { unlocked }.save(&progress_file);
+    }
+}
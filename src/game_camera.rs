@@ -1,18 +1,76 @@
-use bevy::prelude::*;
+use bevy::{input::mouse::MouseMotion, prelude::*};
 
-use crate::player::Player;
+use crate::{
+    actions::{Action, ActionInput},
+    game_state::GameState,
+    player::Player,
+};
 
 pub struct GameCameraPlugin;
 
 impl Plugin for GameCameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_camera)
-            .add_system(look_at_player);
+        app.init_resource::<FreeCamera>()
+            .add_startup_system(setup_camera)
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(toggle_free_camera.label(CameraSystem::Toggle))
+                    .with_system(look_at_player.after(CameraSystem::Toggle))
+                    .with_system(fly_free_camera.after(CameraSystem::Toggle)),
+            );
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum CameraSystem {
+    Toggle,
+}
+
 #[derive(Component)]
-struct ViewCamera;
+pub struct ViewCamera;
+
+/// Detaches the camera from the player so that it can be flown around freely,
+/// e.g. to look at the simulation up close while it is slowed down or frozen.
+#[derive(Default)]
+pub struct FreeCamera {
+    pub enabled: bool,
+}
+
+const FREE_CAMERA_SPEED: f32 = 20.0;
+/// Radians per pixel of mouse movement.
+const FREE_CAMERA_SENSITIVITY: f32 = 0.005;
+
+const GROUND_Y: f32 = 0.0;
+
+/// Casts a ray from the camera through the cursor (in window coordinates) and
+/// returns where it hits the ground plane, if it does.
+pub fn cursor_to_ground(
+    camera: &Camera,
+    camera_transform: &GlobalTransform,
+    window: &Window,
+    cursor: Vec2,
+) -> Option<Vec3> {
+    let window_size = Vec2::new(window.width(), window.height());
+    let ndc = (cursor / window_size) * 2.0 - Vec2::ONE;
+
+    let ndc_to_world = camera_transform.compute_matrix() * camera.projection_matrix.inverse();
+    // the projection uses reversed z, so 1.0 is the near plane
+    let near = ndc_to_world.project_point3(ndc.extend(1.0));
+    let further = ndc_to_world.project_point3(ndc.extend(0.5));
+    let direction = further - near;
+
+    if direction.y.abs() < f32::EPSILON {
+        return None;
+    }
+
+    let distance = (GROUND_Y - near.y) / direction.y;
+
+    if distance < 0.0 {
+        return None;
+    }
+
+    Some(near + direction * distance)
+}
 
 fn setup_camera(mut commands: Commands) {
     commands
@@ -32,10 +90,21 @@ fn setup_camera(mut commands: Commands) {
     });
 }
 
+fn toggle_free_camera(actions: Res<ActionInput>, mut free_camera: ResMut<FreeCamera>) {
+    if actions.just_pressed(Action::ToggleFreeCamera) {
+        free_camera.enabled = !free_camera.enabled;
+    }
+}
+
 fn look_at_player(
+    free_camera: Res<FreeCamera>,
     mut query: Query<&mut Transform, With<ViewCamera>>,
     player_query: Query<&Transform, (With<Player>, Without<ViewCamera>)>,
 ) {
+    if free_camera.enabled {
+        return;
+    }
+
     let mut transform = query.single_mut();
     let player_transform = player_query.single();
 
@@ -43,3 +112,48 @@ fn look_at_player(
         Transform::from_translation(player_transform.translation + Vec3::new(0.0, 60.0, 60.0))
             .looking_at(player_transform.translation, Vec3::new(0.0, 1.0, 0.0));
 }
+
+/// Uses real time, so the camera still moves while the simulation is frozen.
+fn fly_free_camera(
+    free_camera: Res<FreeCamera>,
+    time: Res<Time>,
+    actions: Res<ActionInput>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    mut query: Query<&mut Transform, With<ViewCamera>>,
+) {
+    let rotation = mouse_motion
+        .iter()
+        .fold(Vec2::ZERO, |rotation, motion| rotation + motion.delta);
+
+    if !free_camera.enabled {
+        return;
+    }
+
+    let mut transform = query.single_mut();
+
+    if actions.pressed(Action::TurnFreeCamera) {
+        let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+        let yaw = yaw - rotation.x * FREE_CAMERA_SENSITIVITY;
+        let pitch = (pitch - rotation.y * FREE_CAMERA_SENSITIVITY)
+            .clamp(-std::f32::consts::FRAC_PI_2, std::f32::consts::FRAC_PI_2);
+        transform.rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+    }
+
+    let forward = Vec3::new(transform.forward().x, 0.0, transform.forward().z).normalize_or_zero();
+    let right = Vec3::new(transform.right().x, 0.0, transform.right().z).normalize_or_zero();
+
+    let direction = [
+        (Action::FreeCameraForward, forward),
+        (Action::FreeCameraBack, -forward),
+        (Action::FreeCameraRight, right),
+        (Action::FreeCameraLeft, -right),
+        (Action::FreeCameraUp, Vec3::Y),
+        (Action::FreeCameraDown, -Vec3::Y),
+    ]
+    .into_iter()
+    .filter(|(action, _)| actions.pressed(*action))
+    .fold(Vec3::ZERO, |direction, (_, offset)| direction + offset);
+
+    transform.translation +=
+        direction.normalize_or_zero() * FREE_CAMERA_SPEED * time.delta_seconds();
+}
@@ -0,0 +1,199 @@
+use bevy::{
+    asset::{HandleId, LoadState},
+    prelude::*,
+    utils::HashSet,
+};
+use heron::PhysicsTime;
+use std::iter;
+
+use crate::{
+    actions::{Action, ActionInput},
+    level::CurrentLevel,
+    player::Player,
+    simulation::SimulationStage,
+    tower::TowerHead,
+};
+
+pub struct GameStatePlugin;
+
+impl Plugin for GameStatePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(GameState::MainMenu)
+            // every stage with state-driven systems needs its own driver
+            .add_system_set_to_stage(SimulationStage, State::<GameState>::get_driver())
+            .init_resource::<LoadingAssets>()
+            .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(despawn_level))
+            .add_system_set(SystemSet::on_enter(GameState::Loading).with_system(despawn_level))
+            .add_system_set(
+                SystemSet::on_update(GameState::Loading).with_system(check_assets_loaded),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(check_game_over)
+                    .with_system(handle_restart_input)
+                    .with_system(handle_pause_input),
+            )
+            // anything pushed on top of the level, such as `Paused` or `Controls`,
+            // freezes the physics world until the level is resumed
+            .add_system_set(SystemSet::on_pause(GameState::Playing).with_system(pause_physics))
+            .add_system_set(SystemSet::on_resume(GameState::Playing).with_system(resume_physics))
+            .add_system_set(
+                SystemSet::on_update(GameState::Paused).with_system(handle_resume_input),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Victory).with_system(handle_restart_input),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Defeat).with_system(handle_restart_input),
+            );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GameState {
+    MainMenu,
+    Loading,
+    Playing,
+    Victory,
+    Defeat,
+    /// Pushed on top of `GameState::Playing`, so gameplay systems stop running
+    /// until it is popped again.
+    Paused,
+    LevelSelect,
+    Controls,
+}
+
+/// Marks the root of everything spawned for the current level, which is
+/// despawned when entering `GameState::Loading` or `GameState::MainMenu`.
+#[derive(Component)]
+pub struct LevelEntity;
+
+/// Systems that spawn the level when entering `GameState::Playing`. They run one
+/// after another in this order, so the physics bodies are always created in the
+/// same order, which keeps the simulation reproducible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+pub enum LevelSetup {
+    Terrain,
+    Player,
+    Towers,
+}
+
+/// Assets that must finish loading before leaving `GameState::Loading`.
+/// Plugins push their handles into this during startup, so they can already
+/// load in the background while the main menu is shown.
+#[derive(Default)]
+pub struct LoadingAssets(pub Vec<HandleUntyped>);
+
+/// State changes that are dropped if another one was already queued in the same
+/// frame, instead of failing. The change queued first wins.
+pub trait TryStateChange {
+    fn try_set(&mut self, state: GameState);
+    fn try_push(&mut self, state: GameState);
+    fn try_pop(&mut self);
+}
+
+impl TryStateChange for State<GameState> {
+    fn try_set(&mut self, state: GameState) {
+        let _ = self.set(state);
+    }
+
+    fn try_push(&mut self, state: GameState) {
+        let _ = self.push(state);
+    }
+
+    fn try_pop(&mut self) {
+        let _ = self.pop();
+    }
+}
+
+/// Tears down the current level and spawns it again. This takes priority over
+/// any other state change queued in the same frame, and also unwinds any state
+/// pushed on top of the level, such as `GameState::Paused`.
+pub fn restart_level(state: &mut State<GameState>) {
+    state.overwrite_replace(GameState::Loading).unwrap();
+}
+
+fn despawn_level(mut commands: Commands, query: Query<Entity, With<LevelEntity>>) {
+    query.iter().for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+}
+
+fn handle_restart_input(mut actions: ResMut<ActionInput>, mut state: ResMut<State<GameState>>) {
+    if actions.just_pressed(Action::Restart) {
+        restart_level(&mut state);
+        // the level can finish loading within this frame, and this system would
+        // then see the same press again and restart it over and over
+        actions.clear_just_pressed(Action::Restart);
+    }
+}
+
+// the state changes within this frame, and the system for the new state would
+// then see the same press again and undo it right away
+fn handle_pause_input(mut actions: ResMut<ActionInput>, mut state: ResMut<State<GameState>>) {
+    if actions.just_pressed(Action::Pause) {
+        state.try_push(GameState::Paused);
+        actions.clear_just_pressed(Action::Pause);
+    }
+}
+
+fn handle_resume_input(mut actions: ResMut<ActionInput>, mut state: ResMut<State<GameState>>) {
+    if actions.just_pressed(Action::Pause) {
+        state.try_pop();
+        actions.clear_just_pressed(Action::Pause);
+    }
+}
+
+/// Gameplay systems only run in `GameState::Playing`, but heron keeps stepping
+/// the physics world on its own, so it has to be paused separately.
+fn pause_physics(mut physics_time: ResMut<PhysicsTime>) {
+    physics_time.pause();
+}
+
+fn resume_physics(mut physics_time: ResMut<PhysicsTime>) {
+    physics_time.resume();
+}
+
+/// The assets that failed to load are dropped from `LoadingAssets`, so that
+/// they do not keep other levels from being played.
+fn check_assets_loaded(
+    asset_server: Res<AssetServer>,
+    current_level: Res<CurrentLevel>,
+    mut loading: ResMut<LoadingAssets>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let failed: HashSet<HandleId> = loading
+        .0
+        .iter()
+        .map(|handle| handle.id)
+        .chain(iter::once(current_level.0.id))
+        .filter(|id| asset_server.get_load_state(*id) == LoadState::Failed)
+        .collect();
+
+    if !failed.is_empty() {
+        failed.iter().for_each(|id| {
+            let path = asset_server.get_handle_path(*id);
+            error!("Could not load {:?}, going back to the main menu", path);
+        });
+        loading.0.retain(|handle| !failed.contains(&handle.id));
+        state.set(GameState::MainMenu).unwrap();
+    } else if asset_server.get_group_load_state(loading.0.iter().map(|handle| handle.id))
+        == LoadState::Loaded
+    {
+        state.set(GameState::Playing).unwrap();
+    }
+}
+
+fn check_game_over(
+    player_query: Query<&Player>,
+    tower_query: Query<&TowerHead>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let player = player_query.single();
+
+    if player.health <= 0 {
+        state.try_set(GameState::Defeat);
+    } else if tower_query.iter().all(|head| !head.alive) {
+        state.try_set(GameState::Victory);
+    }
+}
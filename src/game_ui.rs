@@ -1,18 +1,61 @@
 use bevy::prelude::*;
+use heron::Gravity;
 
-use crate::{player::Player, terrain::TERRAIN_SIZE, tower::TowerHead};
+use crate::{
+    actions::{Action, ActionInput},
+    bullets::{shell_impact_point, BulletPool},
+    campaign::Campaign,
+    game_state::{GameState, LevelEntity, LoadingAssets},
+    level::{CurrentLevel, Level},
+    menu::{spawn_button, spawn_screen, spawn_text, MenuButton},
+    player::{
+        cannon_launch, AimElevation, AimInputDirection, Player, PlayerHealthUpdated, SelectedAmmo,
+    },
+    terrain::GROUND_HEIGHT,
+    time_control::TimeControl,
+    tower::TowerHead,
+};
 
 pub struct GameUiPlugin;
 
 impl Plugin for GameUiPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<PlayerHealthUpdated>()
-            .add_startup_system(setup_ui)
-            .add_system(handle_health_updated)
-            .add_system(update_player_dot)
-            .add_system(ensure_enough_tower_dots)
-            .add_system(update_tower_dots)
-            .add_system(update_cannon_status);
+        app.add_startup_system(setup_ui)
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing)
+                    .with_system(setup_hud)
+                    .with_system(setup_impact_marker),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(handle_health_updated)
+                    .with_system(update_player_dot)
+                    .with_system(ensure_enough_tower_dots)
+                    .with_system(update_tower_dots)
+                    .with_system(update_cannon_status)
+                    .with_system(update_time_scale_text)
+                    .with_system(update_bullet_pool_text)
+                    .with_system(update_impact_marker),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameState::Victory).with_system(setup_victory_screen),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Victory).with_system(despawn_overlay_screen),
+            )
+            .add_system_set(SystemSet::on_enter(GameState::Defeat).with_system(setup_defeat_screen))
+            .add_system_set(
+                SystemSet::on_exit(GameState::Defeat).with_system(despawn_overlay_screen),
+            )
+            // the pause screen is hidden while the settings are pushed on top of it
+            .add_system_set(SystemSet::on_enter(GameState::Paused).with_system(setup_pause_screen))
+            .add_system_set(SystemSet::on_resume(GameState::Paused).with_system(setup_pause_screen))
+            .add_system_set(
+                SystemSet::on_exit(GameState::Paused).with_system(despawn_overlay_screen),
+            )
+            .add_system_set(
+                SystemSet::on_pause(GameState::Paused).with_system(despawn_overlay_screen),
+            );
     }
 }
 
@@ -21,6 +64,12 @@ const MAP_SIZE: (f32, f32) = (160.0, 160.0);
 
 const POS_DOT_SIZE: (f32, f32) = (8.0, 8.0);
 
+const OVERLAY_COLOR: Color = Color::rgba(0.0, 0.0, 0.0, 0.6);
+
+const IMPACT_MARKER_RADIUS: f32 = 2.0;
+/// Keeps the marker from flickering into the ground.
+const IMPACT_MARKER_LIFT: f32 = 0.05;
+
 #[derive(Component)]
 struct HealthText;
 
@@ -33,11 +82,34 @@ struct MapTowerDot;
 #[derive(Component)]
 struct CannonText;
 
-pub struct PlayerHealthUpdated(pub i32);
+#[derive(Component)]
+struct TimeScaleText;
+
+#[derive(Component)]
+struct BulletPoolText;
+
+/// Ring on the ground where a shell fired right now would come down.
+#[derive(Component)]
+struct ImpactMarker;
 
-fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
+#[derive(Component)]
+struct OverlayScreen;
+
+fn setup_ui(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut loading: ResMut<LoadingAssets>,
+) {
     commands.spawn_bundle(UiCameraBundle::default());
 
+    loading.0.extend([
+        asset_server.load_untyped("FiraSans-Bold.ttf"),
+        asset_server.load_untyped("map_enclosure.png"),
+        asset_server.load_untyped("map_dot.png"),
+    ]);
+}
+
+fn setup_hud(mut commands: Commands, asset_server: Res<AssetServer>) {
     let font = asset_server.load("FiraSans-Bold.ttf");
 
     commands
@@ -74,7 +146,8 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
             },
             ..Default::default()
         })
-        .insert(HealthText);
+        .insert(HealthText)
+        .insert(LevelEntity);
 
     commands
         .spawn_bundle(TextBundle {
@@ -103,27 +176,80 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
             },
             ..Default::default()
         })
-        .insert(CannonText);
+        .insert(CannonText)
+        .insert(LevelEntity);
 
-    let map_enclosure = asset_server.load("map_enclosure.png");
+    commands
+        .spawn_bundle(TextBundle {
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 40.0,
+                    color: Color::BLACK,
+                },
+                Default::default(),
+            ),
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(0.0),
+                    right: Val::Px(10.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(TimeScaleText)
+        .insert(LevelEntity);
 
-    commands.spawn_bundle(ImageBundle {
-        image: UiImage(map_enclosure),
-        style: Style {
-            position_type: PositionType::Absolute,
-            position: Rect {
-                top: Val::Px(MAP_COORD.1),
-                left: Val::Px(MAP_COORD.0),
+    commands
+        .spawn_bundle(TextBundle {
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: font.clone(),
+                    font_size: 20.0,
+                    color: Color::BLACK,
+                },
+                Default::default(),
+            ),
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(45.0),
+                    right: Val::Px(10.0),
+                    ..Default::default()
+                },
                 ..Default::default()
             },
-            size: Size {
-                width: Val::Px(MAP_SIZE.0),
-                height: Val::Px(MAP_SIZE.1),
+            ..Default::default()
+        })
+        .insert(BulletPoolText)
+        .insert(LevelEntity);
+
+    let map_enclosure = asset_server.load("map_enclosure.png");
+
+    commands
+        .spawn_bundle(ImageBundle {
+            image: UiImage(map_enclosure),
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(MAP_COORD.1),
+                    left: Val::Px(MAP_COORD.0),
+                    ..Default::default()
+                },
+                size: Size {
+                    width: Val::Px(MAP_SIZE.0),
+                    height: Val::Px(MAP_SIZE.1),
+                },
+                ..Default::default()
             },
             ..Default::default()
-        },
-        ..Default::default()
-    });
+        })
+        .insert(LevelEntity);
 
     let map_dot = asset_server.load("map_dot.png");
     commands
@@ -145,7 +271,104 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
             },
             ..Default::default()
         })
-        .insert(MapPlayerDot);
+        .insert(MapPlayerDot)
+        .insert(LevelEntity);
+}
+
+fn setup_victory_screen(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    campaign: Res<Campaign>,
+) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, OVERLAY_COLOR)
+        .insert(OverlayScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "VICTORY", 80.0, Color::GREEN);
+
+            if campaign.has_next() {
+                spawn_text(
+                    parent,
+                    font.clone(),
+                    "All towers have been destroyed.",
+                    30.0,
+                    Color::WHITE,
+                );
+                spawn_button(
+                    parent,
+                    font.clone(),
+                    "Next Level",
+                    Some(MenuButton::NextLevel),
+                );
+            } else {
+                spawn_text(
+                    parent,
+                    font.clone(),
+                    "Campaign complete!",
+                    30.0,
+                    Color::WHITE,
+                );
+            }
+
+            spawn_button(parent, font.clone(), "Retry (R)", Some(MenuButton::Retry));
+            spawn_button(
+                parent,
+                font.clone(),
+                "Level Select",
+                Some(MenuButton::LevelSelect),
+            );
+        });
+}
+
+fn setup_defeat_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, OVERLAY_COLOR)
+        .insert(OverlayScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "DEFEAT", 80.0, Color::RED);
+            spawn_text(
+                parent,
+                font.clone(),
+                "Your tank has been destroyed.",
+                30.0,
+                Color::WHITE,
+            );
+            spawn_button(parent, font.clone(), "Retry (R)", Some(MenuButton::Retry));
+            spawn_button(
+                parent,
+                font.clone(),
+                "Level Select",
+                Some(MenuButton::LevelSelect),
+            );
+        });
+}
+
+fn setup_pause_screen(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, OVERLAY_COLOR)
+        .insert(OverlayScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "PAUSED", 80.0, Color::WHITE);
+            spawn_button(parent, font.clone(), "Resume", Some(MenuButton::Resume));
+            spawn_button(parent, font.clone(), "Restart", Some(MenuButton::Retry));
+            spawn_button(parent, font.clone(), "Settings", Some(MenuButton::Settings));
+            spawn_button(
+                parent,
+                font.clone(),
+                "Main Menu",
+                Some(MenuButton::MainMenu),
+            );
+            spawn_button(parent, font.clone(), "Quit", Some(MenuButton::Quit));
+        });
+}
+
+fn despawn_overlay_screen(mut commands: Commands, query: Query<Entity, With<OverlayScreen>>) {
+    query.iter().for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
 }
 
 fn handle_health_updated(
@@ -160,35 +383,141 @@ fn handle_health_updated(
 }
 
 fn update_cannon_status(
-    player_query: Query<&Player>,
+    player_query: Query<(&Player, &SelectedAmmo)>,
     mut query: Query<&mut Text, With<CannonText>>,
 ) {
-    let player = player_query.single();
+    let (player, ammo) = player_query.single();
     let mut text = query.single_mut();
 
     let (value, color) = if player.shoot_cooldown >= 0.1 {
-        (
-            format!("{}", player.shoot_cooldown.floor().to_string()),
-            Color::RED,
-        )
+        (player.shoot_cooldown.floor().to_string(), Color::RED)
     } else {
-        ("Cannon READY".to_string(), Color::BLACK)
+        (format!("{} READY", ammo.0.name()), Color::BLACK)
     };
 
     text.sections[0].value = value;
     text.sections[0].style.color = color;
 }
 
+fn update_time_scale_text(
+    control: Option<Res<TimeControl>>,
+    mut query: Query<&mut Text, With<TimeScaleText>>,
+) {
+    let control = match control {
+        Some(control) => control,
+        None => return,
+    };
+    let mut text = query.single_mut();
+
+    text.sections[0].value = if control.frozen() {
+        "FROZEN".to_string()
+    } else if (control.scale() - 1.0).abs() > f32::EPSILON {
+        format!("{}x", control.scale())
+    } else {
+        String::new()
+    };
+}
+
+fn update_bullet_pool_text(
+    actions: Res<ActionInput>,
+    pool: Res<BulletPool>,
+    mut shown: Local<bool>,
+    mut query: Query<&mut Text, With<BulletPoolText>>,
+) {
+    if actions.just_pressed(Action::ToggleBulletPoolStats) {
+        *shown = !*shown;
+    }
+    let mut text = query.single_mut();
+
+    text.sections[0].value = if *shown {
+        let stats = pool.stats();
+        format!(
+            "Bullets: {} pooled, {} free, {:.0}% reused",
+            stats.size,
+            stats.free,
+            stats.hit_rate() * 100.0
+        )
+    } else {
+        String::new()
+    };
+}
+
+fn setup_impact_marker(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(
+                shape::Torus {
+                    radius: IMPACT_MARKER_RADIUS,
+                    ring_radius: 0.2,
+                    subdivisions_segments: 24,
+                    subdivisions_sides: 8,
+                }
+                .into(),
+            ),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgb(0.3, 0.5, 1.0),
+                unlit: true,
+                ..Default::default()
+            }),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(ImpactMarker)
+        .insert(LevelEntity);
+}
+
+fn update_impact_marker(
+    gravity: Res<Gravity>,
+    player_query: Query<
+        (
+            &GlobalTransform,
+            &AimInputDirection,
+            &AimElevation,
+            &SelectedAmmo,
+        ),
+        With<Player>,
+    >,
+    mut query: Query<(&mut Transform, &mut Visibility), With<ImpactMarker>>,
+) {
+    let (player_transform, aim, elevation, ammo) = player_query.single();
+    let (mut transform, mut visibility) = query.single_mut();
+
+    let (position, velocity) =
+        cannon_launch(player_transform.translation, aim.0, elevation.0, ammo.0);
+    let impact = (elevation.0 > 0.0)
+        .then(|| {
+            shell_impact_point(
+                position,
+                velocity,
+                gravity.vector(),
+                GROUND_HEIGHT + ammo.0.stats().radius,
+            )
+        })
+        .flatten();
+
+    visibility.is_visible = impact.is_some();
+    if let Some(impact) = impact {
+        transform.translation = Vec3::new(impact.x, IMPACT_MARKER_LIFT, impact.z);
+    }
+}
+
 fn update_player_dot(
     mut query: Query<&mut Style, With<MapPlayerDot>>,
     player_query: Query<&Transform, With<Player>>,
+    levels: Res<Assets<Level>>,
+    current_level: Res<CurrentLevel>,
 ) {
     let player_transform = player_query.single();
     let mut ui = query.single_mut();
+    let terrain_size = levels.get(&current_level.0).unwrap().arena_size;
 
     let player = (
-        (player_transform.translation.x + (TERRAIN_SIZE / 2.0)) / TERRAIN_SIZE,
-        (player_transform.translation.z + (TERRAIN_SIZE / 2.0)) / TERRAIN_SIZE,
+        (player_transform.translation.x + (terrain_size / 2.0)) / terrain_size,
+        (player_transform.translation.z + (terrain_size / 2.0)) / terrain_size,
     );
 
     ui.position.top = Val::Px(MAP_COORD.1 + MAP_SIZE.1 * player.1);
@@ -227,19 +556,24 @@ fn ensure_enough_tower_dots(
                 },
                 ..Default::default()
             })
-            .insert(MapTowerDot);
+            .insert(MapTowerDot)
+            .insert(LevelEntity);
     }
 }
 
 fn update_tower_dots(
     mut query: Query<(&mut Style, &mut UiColor), With<MapTowerDot>>,
     tower_query: Query<(&GlobalTransform, &TowerHead)>,
+    levels: Res<Assets<Level>>,
+    current_level: Res<CurrentLevel>,
 ) {
+    let terrain_size = levels.get(&current_level.0).unwrap().arena_size;
+
     tower_query.iter().zip(query.iter_mut()).for_each(
         |((tower_transform, tower_head), (mut ui, mut color))| {
             let tower = (
-                (tower_transform.translation.x + (TERRAIN_SIZE / 2.0)) / TERRAIN_SIZE,
-                (tower_transform.translation.z + (TERRAIN_SIZE / 2.0)) / TERRAIN_SIZE,
+                (tower_transform.translation.x + (terrain_size / 2.0)) / terrain_size,
+                (tower_transform.translation.z + (terrain_size / 2.0)) / terrain_size,
             );
 
             ui.position.top = Val::Px(MAP_COORD.1 + MAP_SIZE.1 * tower.1);
@@ -0,0 +1,65 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::Deserialize;
+
+use crate::tower::TowerKind;
+
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Level>().init_asset_loader::<LevelLoader>();
+    }
+}
+
+/// Layout of a single map, loaded from a `*.level.ron` file in `assets/levels`.
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "7d0c5a36-5f5e-4b8e-9a55-2f3c8f0f6a41"]
+pub struct Level {
+    pub name: String,
+    pub arena_size: f32,
+    pub player_spawn: Vec3,
+    pub towers: Vec<TowerDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TowerDescriptor {
+    pub position: Vec3,
+    /// Number of cubes stacked to build the tower, including the head.
+    pub height: u32,
+    #[serde(default)]
+    pub kind: TowerKind,
+    /// Overrides the accuracy of the tower's kind, from 0.0 to 1.0.
+    #[serde(default)]
+    pub accuracy: Option<f32>,
+}
+
+pub struct CurrentLevel(pub Handle<Level>);
+
+#[derive(Default)]
+struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let level = ron::de::from_bytes::<Level>(bytes)?;
+            // a tower needs at least its head
+            if let Some(tower) = level.towers.iter().find(|tower| tower.height == 0) {
+                anyhow::bail!("tower at {} has a height of 0", tower.position);
+            }
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level.ron"]
+    }
+}
@@ -0,0 +1,154 @@
+pub mod actions;
+pub mod bullets;
+pub mod campaign;
+pub mod game_camera;
+pub mod game_layer;
+pub mod game_state;
+pub mod game_ui;
+pub mod level;
+pub mod menu;
+pub mod player;
+pub mod replay;
+pub mod ron_file;
+pub mod simulation;
+pub mod terrain;
+pub mod time_control;
+pub mod tower;
+
+use bevy::{app::PluginGroupBuilder, ecs::schedule::ShouldRun, prelude::*};
+use heron::prelude::*;
+use std::mem;
+
+use crate::{actions::BINDINGS_PATH, campaign::PROGRESS_PATH};
+
+pub use crate::actions::{Action, ActionInput, ActionsPlugin, DeviceInputPlugin};
+pub use crate::bullets::{BulletPlugin, BulletPool, BulletPoolStats, BulletType};
+pub use crate::campaign::{Campaign, CampaignPlugin};
+pub use crate::game_camera::{FreeCamera, GameCameraPlugin};
+pub use crate::game_state::{GameState, GameStatePlugin};
+pub use crate::game_ui::GameUiPlugin;
+pub use crate::level::{CurrentLevel, Level, LevelPlugin};
+pub use crate::menu::MenuPlugin;
+pub use crate::player::{
+    AimInputDirection, MovementInputDirection, Player, PlayerHealthUpdated, PlayerHit,
+    PlayerInputPlugin, PlayerPlugin,
+};
+pub use crate::replay::{Replay, ReplayPlayback, ReplayPlugin, ReplayRecorder};
+pub use crate::simulation::{PhysicsSchedule, SimulationPlugin, SimulationRng, SimulationTime};
+pub use crate::terrain::TerrainPlugin;
+pub use crate::time_control::{TimeControl, TimeControlPlugin};
+pub use crate::tower::{TowerAi, TowerAiState, TowerHead, TowerKind, TowerPlugin, TowerStats};
+
+/// Adds heron, with the gravity used by the game. Its systems are put into
+/// `PhysicsSchedule` instead of the app's stages, for `SimulationStage` to run.
+pub struct GamePhysicsPlugin;
+
+impl Plugin for GamePhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        // heron adds its systems to these stages, and its own stages in between
+        let mut physics_schedule = Schedule::default()
+            .with_stage(CoreStage::First, SystemStage::parallel())
+            .with_stage(CoreStage::Update, SystemStage::parallel())
+            .with_stage(CoreStage::PostUpdate, SystemStage::parallel());
+        mem::swap(&mut app.schedule, &mut physics_schedule);
+        app.add_plugin(PhysicsPlugin::default());
+        mem::swap(&mut app.schedule, &mut physics_schedule);
+
+        // the gameplay reads `GlobalTransform`s between frames, so they are updated every tick
+        physics_schedule.add_system_to_stage(
+            CoreStage::Update,
+            bevy::transform::transform_propagate_system::transform_propagate_system,
+        );
+
+        app.insert_resource(PhysicsSchedule(physics_schedule))
+            .insert_resource(Gravity::from(Vec3::new(0.0, -9.81, 0.0)));
+    }
+}
+
+/// Every plugin of the game. This expects `DefaultPlugins` to be added first.
+///
+/// Individual plugins can be left out with `PluginGroupBuilder::disable`, for
+/// example `DeviceInputPlugin` when the app fills `ActionInput` itself.
+pub struct TankGamePlugins;
+
+impl PluginGroup for TankGamePlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group
+            .add(GamePhysicsPlugin)
+            .add(SimulationPlugin)
+            .add(ActionsPlugin {
+                save_path: Some(BINDINGS_PATH.to_string()),
+            })
+            .add(DeviceInputPlugin)
+            .add(GameStatePlugin)
+            .add(LevelPlugin)
+            .add(CampaignPlugin {
+                save_path: Some(PROGRESS_PATH.to_string()),
+            })
+            .add(GameCameraPlugin)
+            .add(TerrainPlugin)
+            .add(PlayerPlugin)
+            .add(PlayerInputPlugin)
+            .add(TowerPlugin)
+            .add(BulletPlugin)
+            .add(ReplayPlugin::default())
+            .add(TimeControlPlugin)
+            .add(GameUiPlugin)
+            .add(MenuPlugin);
+    }
+}
+
+/// The gameplay plugins only, without any window, rendering, UI or device
+/// input, so the game can be simulated on machines without a display. This
+/// expects `MinimalPlugins`, `AssetPlugin` and `TransformPlugin` to be added
+/// first.
+///
+/// Nothing reads the keyboard, mouse or gamepads, so `ActionInput` and the
+/// player's `MovementInputDirection` and `AimInputDirection` are left for the
+/// app to drive.
+///
+/// Nothing is saved, and neither the bindings nor the progress through the
+/// campaign are loaded from disk, so runs do not depend on earlier ones.
+pub struct HeadlessPlugins;
+
+impl PluginGroup for HeadlessPlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group
+            .add(HeadlessRenderPlugin)
+            .add(GamePhysicsPlugin)
+            .add(SimulationPlugin)
+            .add(ActionsPlugin::default())
+            .add(GameStatePlugin)
+            .add(LevelPlugin)
+            .add(CampaignPlugin::default())
+            .add(TerrainPlugin)
+            .add(PlayerPlugin)
+            .add(TowerPlugin)
+            .add(BulletPlugin);
+    }
+}
+
+/// Marks an app that is not drawn, see `HeadlessPlugins`.
+pub struct Headless;
+
+/// Stands in for the rendering plugins, so that the gameplay plugins can build
+/// meshes and materials without them.
+pub struct HeadlessRenderPlugin;
+
+impl Plugin for HeadlessRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .insert_resource(Headless);
+    }
+}
+
+/// Run criteria for loading models and textures, which need the rendering
+/// plugins.
+pub fn rendering(headless: Option<Res<Headless>>) -> ShouldRun {
+    if headless.is_some() {
+        ShouldRun::No
+    } else {
+        ShouldRun::Yes
+    }
+}
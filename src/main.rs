@@ -1,31 +1,24 @@
-mod bullets;
-mod game_camera;
-mod game_layer;
-mod game_ui;
-mod player;
-mod terrain;
-mod tower;
-
 use bevy::prelude::*;
-use heron::prelude::*;
-
-use crate::bullets::BulletPlugin;
-use crate::game_camera::GameCameraPlugin;
-use crate::game_ui::GameUiPlugin;
-use crate::player::PlayerPlugin;
-use crate::terrain::TerrainPlugin;
-use crate::tower::TowerPlugin;
+use std::env;
+use tank_game_bevy::{Replay, ReplayPlayback, ReplayPlugin, TankGamePlugins};
 
 fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins)
-        .add_plugin(PhysicsPlugin::default())
-        .insert_resource(Gravity::from(Vec3::new(0.0, -9.81, 0.0)))
-        .add_plugin(GameCameraPlugin)
-        .add_plugin(TerrainPlugin)
-        .add_plugin(PlayerPlugin)
-        .add_plugin(TowerPlugin)
-        .add_plugin(BulletPlugin)
-        .add_plugin(GameUiPlugin)
-        .run();
+    let mut app = App::new();
+    app.add_plugins(DefaultPlugins)
+        .add_plugins_with(TankGamePlugins, |group| group.disable::<ReplayPlugin>())
+        .add_plugin(ReplayPlugin {
+            save_path: Some("last_replay.ron".to_string()),
+        });
+
+    // `--replay <file>` plays back a saved replay right away
+    if let Some(path) = env::args().skip_while(|arg| arg != "--replay").nth(1) {
+        match Replay::load(&path) {
+            Ok(replay) => {
+                app.insert_resource(ReplayPlayback::new(replay));
+            }
+            Err(err) => error!("Could not load replay {}: {}", path, err),
+        }
+    }
+
+    app.run();
 }
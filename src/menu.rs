@@ -0,0 +1,470 @@
+use bevy::{app::AppExit, input::mouse::MouseWheel, prelude::*};
+
+use crate::{
+    actions::{Action, ActionInput, Binding, InputBindings},
+    campaign::{play_level, Campaign},
+    game_state::{restart_level, GameState, TryStateChange},
+    level::{CurrentLevel, Level},
+    player::DrivingMode,
+};
+
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingRebind>()
+            .add_system(handle_menu_buttons.label(MenuSystem::Buttons))
+            .add_system(handle_open_controls)
+            .add_system_set(SystemSet::on_enter(GameState::MainMenu).with_system(setup_main_menu))
+            .add_system_set(
+                SystemSet::on_exit(GameState::MainMenu).with_system(despawn_menu_screen),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameState::LevelSelect).with_system(setup_level_select),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::LevelSelect).with_system(despawn_menu_screen),
+            )
+            .add_system_set(SystemSet::on_enter(GameState::Controls).with_system(setup_controls))
+            .add_system_set(
+                SystemSet::on_update(GameState::Controls)
+                    .with_system(capture_rebind.before(MenuSystem::Buttons))
+                    .with_system(update_binding_labels)
+                    .with_system(update_driving_mode_label),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameState::Controls).with_system(despawn_menu_screen),
+            );
+    }
+}
+
+const BUTTON_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+const BUTTON_HOVERED_COLOR: Color = Color::rgb(0.3, 0.3, 0.3);
+const BUTTON_LOCKED_COLOR: Color = Color::rgb(0.4, 0.4, 0.4);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum MenuSystem {
+    Buttons,
+}
+
+#[derive(Component, Clone, Copy)]
+pub enum MenuButton {
+    Play,
+    Resume,
+    Retry,
+    NextLevel,
+    LevelSelect,
+    PlayLevel(usize),
+    Rebind(Action),
+    CancelRebind,
+    ResetBindings,
+    ToggleDrivingMode,
+    Settings,
+    Back,
+    MainMenu,
+    Quit,
+}
+
+/// The action waiting for a key or mouse button to be bound to it.
+#[derive(Default)]
+struct PendingRebind(Option<Action>);
+
+/// Root of a full-screen menu, despawned when its state is exited.
+#[derive(Component)]
+struct MenuScreen;
+
+pub fn spawn_screen<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    background: Color,
+) -> bevy::ecs::system::EntityCommands<'w, 's, 'a> {
+    commands.spawn_bundle(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: Rect {
+                top: Val::Px(0.0),
+                left: Val::Px(0.0),
+                ..Default::default()
+            },
+            size: Size {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+            },
+            flex_direction: FlexDirection::ColumnReverse,
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        color: background.into(),
+        ..Default::default()
+    })
+}
+
+pub fn spawn_text(
+    parent: &mut ChildBuilder,
+    font: Handle<Font>,
+    value: &str,
+    font_size: f32,
+    color: Color,
+) {
+    parent.spawn_bundle(TextBundle {
+        text: Text::with_section(
+            value,
+            TextStyle {
+                font,
+                font_size,
+                color,
+            },
+            Default::default(),
+        ),
+        ..Default::default()
+    });
+}
+
+/// Spawns a button that triggers `button` when clicked. Passing `None` spawns
+/// a greyed out button that cannot be clicked.
+pub fn spawn_button(
+    parent: &mut ChildBuilder,
+    font: Handle<Font>,
+    label: &str,
+    button: Option<MenuButton>,
+) {
+    let mut entity = parent.spawn_bundle(ButtonBundle {
+        style: Style {
+            size: Size {
+                width: Val::Px(260.0),
+                height: Val::Px(60.0),
+            },
+            margin: Rect::all(Val::Px(10.0)),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..Default::default()
+        },
+        color: if button.is_some() {
+            BUTTON_COLOR
+        } else {
+            BUTTON_LOCKED_COLOR
+        }
+        .into(),
+        ..Default::default()
+    });
+
+    if let Some(button) = button {
+        entity.insert(button);
+    }
+
+    entity.with_children(|parent| {
+        spawn_text(parent, font, label, 30.0, Color::WHITE);
+    });
+}
+
+fn setup_main_menu(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, Color::rgb(0.1, 0.1, 0.1))
+        .insert(MenuScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "TANK GAME", 80.0, Color::WHITE);
+            spawn_button(parent, font.clone(), "Play", Some(MenuButton::Play));
+            spawn_button(
+                parent,
+                font.clone(),
+                "Level Select",
+                Some(MenuButton::LevelSelect),
+            );
+            spawn_button(parent, font.clone(), "Settings", Some(MenuButton::Settings));
+            spawn_button(parent, font.clone(), "Quit", Some(MenuButton::Quit));
+        });
+}
+
+fn setup_level_select(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    campaign: Res<Campaign>,
+    levels: Res<Assets<Level>>,
+) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, Color::rgb(0.1, 0.1, 0.1))
+        .insert(MenuScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "SELECT LEVEL", 60.0, Color::WHITE);
+
+            campaign.levels.iter().enumerate().for_each(|(i, handle)| {
+                // a level that has not loaded (yet) goes by its file name
+                let name = match levels.get(handle) {
+                    Some(level) => level.name.clone(),
+                    None => asset_server
+                        .get_handle_path(handle)
+                        .map(|path| path.path().display().to_string())
+                        .unwrap_or_default(),
+                };
+                let label = format!("{}. {}", i + 1, name);
+                let button = campaign.is_unlocked(i).then(|| MenuButton::PlayLevel(i));
+
+                spawn_button(parent, font.clone(), &label, button);
+            });
+
+            spawn_button(
+                parent,
+                font.clone(),
+                "Main Menu",
+                Some(MenuButton::MainMenu),
+            );
+        });
+}
+
+fn binding_label(action: Action, bindings: &InputBindings, pending: &PendingRebind) -> String {
+    if pending.0 == Some(action) {
+        format!("{}: press a key...", action.name())
+    } else {
+        format!("{}: {}", action.name(), bindings.get(action).name())
+    }
+}
+
+fn driving_mode_label(driving_mode: DrivingMode) -> String {
+    format!("Driving: {}", driving_mode.name())
+}
+
+fn setup_controls(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    bindings: Res<InputBindings>,
+    pending: Res<PendingRebind>,
+    driving_mode: Res<DrivingMode>,
+) {
+    let font = asset_server.load("FiraSans-Bold.ttf");
+
+    spawn_screen(&mut commands, Color::rgb(0.1, 0.1, 0.1))
+        .insert(MenuScreen)
+        .with_children(|parent| {
+            spawn_text(parent, font.clone(), "CONTROLS", 60.0, Color::WHITE);
+            spawn_text(
+                parent,
+                font.clone(),
+                "Click an action, then press the key or mouse button to bind to it. A binding that is taken is swapped with the other action.",
+                20.0,
+                Color::WHITE,
+            );
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size {
+                            width: Val::Px(1140.0),
+                            height: Val::Auto,
+                        },
+                        flex_wrap: FlexWrap::WrapReverse,
+                        justify_content: JustifyContent::Center,
+                        ..Default::default()
+                    },
+                    color: Color::NONE.into(),
+                    ..Default::default()
+                })
+                .with_children(|parent| {
+                    Action::ALL.iter().for_each(|action| {
+                        parent
+                            .spawn_bundle(ButtonBundle {
+                                style: Style {
+                                    size: Size {
+                                        width: Val::Px(360.0),
+                                        height: Val::Px(40.0),
+                                    },
+                                    margin: Rect::all(Val::Px(5.0)),
+                                    justify_content: JustifyContent::Center,
+                                    align_items: AlignItems::Center,
+                                    ..Default::default()
+                                },
+                                color: BUTTON_COLOR.into(),
+                                ..Default::default()
+                            })
+                            .insert(MenuButton::Rebind(*action))
+                            .with_children(|parent| {
+                                spawn_text(
+                                    parent,
+                                    font.clone(),
+                                    &binding_label(*action, &bindings, &pending),
+                                    22.0,
+                                    Color::WHITE,
+                                );
+                            });
+                    });
+                });
+
+            spawn_button(
+                parent,
+                font.clone(),
+                &driving_mode_label(*driving_mode),
+                Some(MenuButton::ToggleDrivingMode),
+            );
+            spawn_button(
+                parent,
+                font.clone(),
+                "Cancel Rebind",
+                Some(MenuButton::CancelRebind),
+            );
+            spawn_button(
+                parent,
+                font.clone(),
+                "Reset Defaults",
+                Some(MenuButton::ResetBindings),
+            );
+            spawn_button(parent, font.clone(), "Back", Some(MenuButton::Back));
+        });
+}
+
+fn handle_open_controls(actions: Res<ActionInput>, mut state: ResMut<State<GameState>>) {
+    if actions.just_pressed(Action::OpenControls)
+        && !matches!(state.current(), GameState::Loading | GameState::Controls)
+    {
+        state.try_push(GameState::Controls);
+    }
+}
+
+/// Binds the next pressed key, mouse button or scroll to the pending action. This runs
+/// before the buttons are handled, so the click that started the rebind is not
+/// picked up as the new binding. Any key can be bound, so the rebind is only
+/// cancelled by clicking `MenuButton::CancelRebind`.
+fn capture_rebind(
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    buttons: Query<(&Interaction, &MenuButton)>,
+    mut pending: ResMut<PendingRebind>,
+    mut bindings: ResMut<InputBindings>,
+) {
+    // read even while nothing is pending, so that old scrolls are not picked up
+    let scrolled: f32 = mouse_wheel.iter().map(|event| event.y).sum();
+
+    let action = match pending.0 {
+        Some(action) => action,
+        None => return,
+    };
+
+    // the click is left for `handle_menu_buttons`, which cancels the rebind
+    let cancelled = buttons.iter().any(|(interaction, button)| {
+        *interaction == Interaction::Clicked && matches!(button, MenuButton::CancelRebind)
+    });
+    if cancelled {
+        return;
+    }
+
+    let binding = keyboard_input
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or(if scrolled > 0.0 {
+            Some(Binding::WheelUp)
+        } else if scrolled < 0.0 {
+            Some(Binding::WheelDown)
+        } else {
+            None
+        });
+
+    if let Some(binding) = binding {
+        bindings.set(action, binding);
+        bindings.save();
+        pending.0 = None;
+    }
+}
+
+fn update_binding_labels(
+    bindings: Res<InputBindings>,
+    pending: Res<PendingRebind>,
+    button_query: Query<(&MenuButton, &Children)>,
+    mut text_query: Query<&mut Text>,
+) {
+    if !bindings.is_changed() && !pending.is_changed() {
+        return;
+    }
+
+    button_query.iter().for_each(|(button, children)| {
+        if let MenuButton::Rebind(action) = button {
+            if let Ok(mut text) = text_query.get_mut(children[0]) {
+                text.sections[0].value = binding_label(*action, &bindings, &pending);
+            }
+        }
+    });
+}
+
+fn update_driving_mode_label(
+    driving_mode: Res<DrivingMode>,
+    button_query: Query<(&MenuButton, &Children)>,
+    mut text_query: Query<&mut Text>,
+) {
+    if !driving_mode.is_changed() {
+        return;
+    }
+
+    button_query.iter().for_each(|(button, children)| {
+        if let MenuButton::ToggleDrivingMode = button {
+            if let Ok(mut text) = text_query.get_mut(children[0]) {
+                text.sections[0].value = driving_mode_label(*driving_mode);
+            }
+        }
+    });
+}
+
+fn despawn_menu_screen(mut commands: Commands, query: Query<Entity, With<MenuScreen>>) {
+    query.iter().for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_menu_buttons(
+    mut query: Query<(&Interaction, &MenuButton, &mut UiColor), Changed<Interaction>>,
+    mut state: ResMut<State<GameState>>,
+    mut campaign: ResMut<Campaign>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut bindings: ResMut<InputBindings>,
+    mut pending: ResMut<PendingRebind>,
+    mut driving_mode: ResMut<DrivingMode>,
+    mut exit: EventWriter<AppExit>,
+) {
+    query
+        .iter_mut()
+        .for_each(|(interaction, button, mut color)| match interaction {
+            Interaction::Clicked => match *button {
+                MenuButton::Play => {
+                    let current = campaign.current;
+                    play_level(current, &mut campaign, &mut current_level, &mut state);
+                }
+                MenuButton::Resume => state.try_pop(),
+                MenuButton::Retry => restart_level(&mut state),
+                MenuButton::NextLevel => {
+                    let next = campaign.current + 1;
+                    play_level(next, &mut campaign, &mut current_level, &mut state);
+                }
+                MenuButton::LevelSelect => state.try_set(GameState::LevelSelect),
+                MenuButton::PlayLevel(index) => {
+                    play_level(index, &mut campaign, &mut current_level, &mut state);
+                }
+                MenuButton::Rebind(action) => pending.0 = Some(action),
+                MenuButton::CancelRebind => pending.0 = None,
+                MenuButton::ResetBindings => {
+                    bindings.reset();
+                    bindings.save();
+                    pending.0 = None;
+                }
+                MenuButton::ToggleDrivingMode => *driving_mode = driving_mode.toggled(),
+                MenuButton::Settings => state.try_push(GameState::Controls),
+                MenuButton::Back => {
+                    pending.0 = None;
+                    state.try_pop();
+                }
+                // like a restart, leaving the level wins over any queued change
+                MenuButton::MainMenu => {
+                    let _ = state.overwrite_replace(GameState::MainMenu);
+                }
+                MenuButton::Quit => exit.send(AppExit),
+            },
+            Interaction::Hovered => *color = BUTTON_HOVERED_COLOR.into(),
+            Interaction::None => *color = BUTTON_COLOR.into(),
+        });
+}
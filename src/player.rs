@@ -1,64 +1,210 @@
 use bevy::prelude::*;
 use heron::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
 
 use crate::{
-    bullets::{BulletAssets, BulletType},
+    actions::{Action, ActionInput},
+    bullets::{AmmoKind, BulletAssets, BulletPool, BulletType, BULLET_SPEED, SHELL_SPEED},
+    game_camera::{cursor_to_ground, ViewCamera},
     game_layer::GameLayer,
-    game_ui::PlayerHealthUpdated,
+    game_state::{GameState, LevelEntity, LevelSetup, LoadingAssets},
+    level::{CurrentLevel, Level},
+    rendering,
+    simulation::{SimulationEvents, SimulationStage, SimulationTime},
 };
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_event::<PlayerHit>()
-            .add_startup_system(setup_player)
-            .add_system(handle_player_movement_input)
-            .add_system(handle_player_movement)
-            .add_system(handle_player_aim_input)
-            .add_system(handle_player_aim)
-            .add_system(handle_player_hit)
-            .add_system(handle_player_shoot_input)
-            .add_system(handle_player_hit_recovery)
-            .add_system(shoot_cooldown);
+        app.add_simulation_event::<PlayerHit>()
+            .add_event::<PlayerHealthUpdated>()
+            .init_resource::<DrivingMode>()
+            .add_startup_system(load_player_assets.with_run_criteria(rendering))
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing).with_system(
+                    setup_player
+                        .label(LevelSetup::Player)
+                        .after(LevelSetup::Terrain),
+                ),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing)
+                    .with_system(handle_player_aim.label(PlayerSystem::Aim))
+                    .with_system(handle_player_fire_input)
+                    .with_system(handle_player_elevation_input)
+                    .with_system(handle_ammo_selection),
+            )
+            .add_system_set_to_stage(
+                SimulationStage,
+                SystemSet::on_update(GameState::Playing)
+                    .label(PlayerSystem::Simulate)
+                    .with_system(handle_player_hit.label(PlayerTick::Hit))
+                    .with_system(
+                        handle_player_hit_recovery
+                            .label(PlayerTick::Recover)
+                            .after(PlayerTick::Hit),
+                    )
+                    .with_system(
+                        handle_player_movement
+                            .label(PlayerTick::Move)
+                            .after(PlayerTick::Recover),
+                    )
+                    .with_system(
+                        shoot_cooldown
+                            .label(PlayerTick::Cooldown)
+                            .after(PlayerTick::Move),
+                    )
+                    .with_system(handle_player_shoot.after(PlayerTick::Cooldown)),
+            );
     }
 }
 
-const ROTATION_SPEED: f32 = 0.2;
-const MOVING_SPEED: f32 = 200.0;
+pub struct PlayerInputPlugin;
+
+impl Plugin for PlayerInputPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_set(
+            SystemSet::on_update(GameState::Playing)
+                .with_system(handle_player_movement_input)
+                .with_system(handle_player_aim_input.before(PlayerSystem::Aim)),
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+pub enum PlayerSystem {
+    Aim,
+    Simulate,
+}
+
+/// Systems in `SimulationStage` that share data are always run in the same
+/// order, otherwise it is picked at random and the same input could play out
+/// differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum PlayerTick {
+    Hit,
+    Recover,
+    Move,
+    Cooldown,
+}
+
+/// In radians per second.
+const ROTATION_SPEED: f32 = 1.2;
+/// In units per second.
+const MOVING_SPEED: f32 = 3.3;
 const INITIAL_HEALTH: i32 = 10;
 const PLAYER_SIZE: (f32, f32, f32) = (8.0, 3.0, 4.0);
 const HIT_DISABLE_TIME: f32 = 2.0;
-const SHOOT_COOLDOWN: f32 = 3.0;
+/// Cooldown after firing standard ammo. Other ammo has its own.
+pub const SHOOT_COOLDOWN: f32 = 3.0;
+const STICK_DEADZONE: f32 = 0.2;
+/// Highest angle above the horizon that shells can be lobbed at, in radians.
+pub const MAX_ELEVATION: f32 = PI / 3.0;
+const ELEVATION_STEP: f32 = PI / 36.0;
 
 #[derive(Component)]
 pub struct Player {
-    health: i32,
+    pub health: i32,
     hit_recovery: f32,
     pub shoot_cooldown: f32,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DrivingMode {
+    /// The tank turns towards the input direction on screen, then drives forward.
+    WorldRelative,
+    /// Forward/back drive along the hull, left/right rotate the hull in place.
+    TankRelative,
+}
+
+impl Default for DrivingMode {
+    fn default() -> Self {
+        DrivingMode::WorldRelative
+    }
+}
+
+impl DrivingMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            DrivingMode::WorldRelative => "World relative",
+            DrivingMode::TankRelative => "Tank relative",
+        }
+    }
+
+    pub fn toggled(self) -> Self {
+        match self {
+            DrivingMode::WorldRelative => DrivingMode::TankRelative,
+            DrivingMode::TankRelative => DrivingMode::WorldRelative,
+        }
+    }
+}
+
+/// x is left (+1) / right (-1), z is forward (+1) / back (-1).
 #[derive(Component)]
-struct MovementInputDirection(Vec3);
+pub struct MovementInputDirection(pub Vec3);
 
 pub struct PlayerHit;
 
+pub struct PlayerHealthUpdated(pub i32);
+
 #[derive(Component)]
-struct AimInputDirection(Vec3);
+pub struct AimInputDirection(pub Vec3);
+
+/// Whether fire was pressed since the last simulation tick. Frames can be
+/// shorter than ticks, so presses are kept until a tick picks them up.
+#[derive(Component)]
+pub struct FireInput(pub bool);
+
+#[derive(Component)]
+pub struct SelectedAmmo(pub AmmoKind);
+
+/// Angle above the horizon that the cannon fires at, in radians. At zero it
+/// fires a straight bullet, otherwise a shell that arcs under gravity.
+#[derive(Component)]
+pub struct AimElevation(pub f32);
 
 #[derive(Component)]
 struct TankTop;
 
-fn setup_player(
+struct PlayerModels {
+    bottom: Handle<Scene>,
+    turret: Handle<Scene>,
+    barrel: Handle<Scene>,
+}
+
+fn load_player_assets(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
+    mut loading: ResMut<LoadingAssets>,
+) {
+    loading.0.extend([
+        asset_server.load_untyped("tank_bottom.glb"),
+        asset_server.load_untyped("tank_turret.glb"),
+        asset_server.load_untyped("tank_barrel.glb"),
+    ]);
+
+    commands.insert_resource(PlayerModels {
+        bottom: asset_server.load("tank_bottom.glb#Scene0"),
+        turret: asset_server.load("tank_turret.glb#Scene0"),
+        barrel: asset_server.load("tank_barrel.glb#Scene0"),
+    });
+}
+
+fn setup_player(
+    mut commands: Commands,
+    models: Option<Res<PlayerModels>>,
     mut health_updated: EventWriter<PlayerHealthUpdated>,
+    levels: Res<Assets<Level>>,
+    current_level: Res<CurrentLevel>,
 ) {
+    let level = levels.get(&current_level.0).expect("level is not loaded");
+
     commands
         .spawn_bundle((
             Transform {
-                translation: Vec3::new(0.0, 3.0, 0.0),
+                translation: level.player_spawn,
                 ..Default::default()
             },
             GlobalTransform::identity(),
@@ -78,27 +224,33 @@ fn setup_player(
             ..Default::default()
         })
         .insert(Velocity::default())
+        .insert(LevelEntity)
         .insert(Player {
             health: INITIAL_HEALTH,
             hit_recovery: 0.0,
             shoot_cooldown: 0.0,
         })
         .insert(MovementInputDirection(Vec3::ZERO))
-        .insert(AimInputDirection(Vec3::ZERO))
+        .insert(AimInputDirection(Vec3::X))
+        .insert(FireInput(false))
+        .insert(AimElevation(0.0))
+        .insert(SelectedAmmo(AmmoKind::Standard))
         .with_children(|parent| {
             parent
                 .spawn_bundle((Transform::default(), GlobalTransform::identity()))
                 .with_children(|gparent| {
-                    gparent.spawn_scene(asset_server.load("tank_bottom.glb#Scene0"));
+                    if let Some(models) = &models {
+                        gparent.spawn_scene(models.bottom.clone());
+                    }
                 });
             parent
                 .spawn_bundle((Transform::default(), GlobalTransform::identity()))
                 .insert(TankTop)
                 .with_children(|gparent| {
-                    gparent.spawn_scene(asset_server.load("tank_turret.glb#Scene0"));
-                })
-                .with_children(|gparent| {
-                    gparent.spawn_scene(asset_server.load("tank_barrel.glb#Scene0"));
+                    if let Some(models) = &models {
+                        gparent.spawn_scene(models.turret.clone());
+                        gparent.spawn_scene(models.barrel.clone());
+                    }
                 });
         })
         .insert(
@@ -110,16 +262,35 @@ fn setup_player(
     health_updated.send(PlayerHealthUpdated(INITIAL_HEALTH));
 }
 
+/// Returns the position of the given stick on the first gamepad where it is pushed past the deadzone.
+fn gamepad_stick(
+    gamepads: &Gamepads,
+    axes: &Axis<GamepadAxis>,
+    x: GamepadAxisType,
+    y: GamepadAxisType,
+) -> Option<Vec2> {
+    gamepads.iter().find_map(|gamepad| {
+        let stick = Vec2::new(
+            axes.get(GamepadAxis(*gamepad, x))?,
+            axes.get(GamepadAxis(*gamepad, y))?,
+        );
+
+        (stick.length() > STICK_DEADZONE).then(|| stick)
+    })
+}
+
 fn handle_player_movement_input(
-    keyboard_input: Res<Input<KeyCode>>,
+    actions: Res<ActionInput>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
     mut query: Query<&mut MovementInputDirection, With<Player>>,
 ) {
     let mut input_direction = query.single_mut();
 
-    let forward = keyboard_input.pressed(KeyCode::W);
-    let backward = keyboard_input.pressed(KeyCode::S);
-    let left = keyboard_input.pressed(KeyCode::A);
-    let right = keyboard_input.pressed(KeyCode::D);
+    let forward = actions.pressed(Action::MoveForward);
+    let backward = actions.pressed(Action::MoveBack);
+    let left = actions.pressed(Action::TurnLeft);
+    let right = actions.pressed(Action::TurnRight);
 
     input_direction.0 = Vec3::new(
         if left && !right {
@@ -138,10 +309,27 @@ fn handle_player_movement_input(
             0.0
         },
     );
+
+    if input_direction.0 == Vec3::ZERO {
+        if let Some(stick) = gamepad_stick(
+            &gamepads,
+            &axes,
+            GamepadAxisType::LeftStickX,
+            GamepadAxisType::LeftStickY,
+        ) {
+            input_direction.0 = Vec3::new(-stick.x, 0.0, stick.y);
+        }
+    }
+}
+
+/// Wraps an angle into the `[-PI, PI)` range.
+pub fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
 }
 
 fn handle_player_movement(
-    time: Res<Time>,
+    time: Res<SimulationTime>,
+    driving_mode: Res<DrivingMode>,
     mut query: Query<(&MovementInputDirection, &mut Velocity, &Transform, &Player)>,
 ) {
     let (dir, mut velocity, transform, player) = query.single_mut();
@@ -151,44 +339,46 @@ fn handle_player_movement(
         return;
     }
 
-    if dir.0.x != 0.0 || dir.0.z != 0.0 {
-        // TODO: See whether we can improve this system
-        // Problem 1: When in the target direction, the turning can overshoot and cause jittering
-        let facing_direction = transform.local_x();
-
-        velocity.linear = Vec3::new(
-            facing_direction.x * MOVING_SPEED * time.delta_seconds(),
-            velocity.linear.y,
-            facing_direction.z * MOVING_SPEED * time.delta_seconds(),
-        );
-
-        let facing_direction = facing_direction.z.atan2(facing_direction.x);
-        let target_direction = dir.0.z.atan2(dir.0.x);
-
-        let delta_angle = if facing_direction > target_direction {
-            let left = (target_direction + 2.0 * PI) - facing_direction;
-            let right = facing_direction - target_direction;
+    if dir.0.x == 0.0 && dir.0.z == 0.0 {
+        velocity.linear = Vec3::new(0.0, velocity.linear.y, 0.0);
+        velocity.angular = AxisAngle::new(Vec3::Y, 0.0);
+        return;
+    }
 
-            if left < right {
-                1.0
-            } else {
-                -1.0
-            }
-        } else {
-            let left = target_direction - facing_direction;
-            let right = facing_direction - (target_direction - 2.0 * PI);
-
-            if left < right {
-                1.0
-            } else {
-                -1.0
-            }
-        };
-
-        velocity.angular = AxisAngle::new(
-            Vec3::new(0.0, 1.0, 0.0),
-            delta_angle * 360.0 * ROTATION_SPEED * time.delta_seconds(),
-        );
+    let facing_direction = transform.local_x();
+
+    match *driving_mode {
+        DrivingMode::WorldRelative => {
+            velocity.linear = Vec3::new(
+                facing_direction.x * MOVING_SPEED,
+                velocity.linear.y,
+                facing_direction.z * MOVING_SPEED,
+            );
+
+            // angles are measured around +y, the same way as the tank's rotation, and the
+            // camera looks down -z, so forward on screen is -z and left on screen is -x
+            let facing_angle = (-facing_direction.z).atan2(facing_direction.x);
+            let target_angle = dir.0.z.atan2(-dir.0.x);
+            let remaining_angle = wrap_angle(target_angle - facing_angle);
+
+            // never turn further than the remaining angle within a tick, so the tank
+            // does not overshoot the target direction and jitter around it
+            let max_rotation_speed = remaining_angle.abs() / time.delta_seconds();
+
+            velocity.angular = AxisAngle::new(
+                Vec3::Y,
+                remaining_angle.signum() * ROTATION_SPEED.min(max_rotation_speed),
+            );
+        }
+        DrivingMode::TankRelative => {
+            velocity.linear = Vec3::new(
+                facing_direction.x * MOVING_SPEED * dir.0.z,
+                velocity.linear.y,
+                facing_direction.z * MOVING_SPEED * dir.0.z,
+            );
+
+            velocity.angular = AxisAngle::new(Vec3::Y, dir.0.x * ROTATION_SPEED);
+        }
     }
 }
 
@@ -213,16 +403,73 @@ fn handle_player_hit(
 
 fn handle_player_aim_input(
     windows: Res<Windows>,
-    mut query: Query<&mut AimInputDirection, With<Player>>,
+    mut cursor_moved: EventReader<CursorMoved>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<ViewCamera>>,
+    mut query: Query<(&mut AimInputDirection, &Transform), With<Player>>,
+    mut aiming_with_gamepad: Local<bool>,
 ) {
-    let window = windows.get_primary().unwrap();
+    if let Some(stick) = gamepad_stick(
+        &gamepads,
+        &axes,
+        GamepadAxisType::RightStickX,
+        GamepadAxisType::RightStickY,
+    ) {
+        let (mut input, _) = query.single_mut();
+
+        // the camera looks down the -z axis, so pushing the stick up aims towards -z
+        input.0 = Vec3::new(stick.x, 0.0, -stick.y).normalize();
+        *aiming_with_gamepad = true;
+        return;
+    }
 
-    if let Some(pos) = window.cursor_position() {
-        let mut input = query.single_mut();
+    // the stick keeps its last aim until the mouse is used again
+    if cursor_moved.iter().count() > 0 {
+        *aiming_with_gamepad = false;
+    }
 
-        let middle = Vec2::new(window.width() / 2.0, window.height() / 2.0);
-        let aim_direction = (pos - middle).normalize();
-        input.0 = Vec3::new(aim_direction.x, 0.0, aim_direction.y);
+    if *aiming_with_gamepad {
+        return;
+    }
+
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let (camera, camera_transform) = camera_query.single();
+
+    if let Some(target) = window
+        .cursor_position()
+        .and_then(|pos| cursor_to_ground(camera, camera_transform, window, pos))
+    {
+        let (mut input, transform) = query.single_mut();
+
+        let direction = target - transform.translation;
+        let direction = Vec3::new(direction.x, 0.0, direction.z);
+
+        // keep the previous aim when the cursor is right on top of the tank
+        if direction.length_squared() > f32::EPSILON {
+            input.0 = direction.normalize();
+        }
+    }
+}
+
+fn handle_player_elevation_input(
+    actions: Res<ActionInput>,
+    mut query: Query<&mut AimElevation, With<Player>>,
+) {
+    let mut steps = 0.0;
+    if actions.just_pressed(Action::RaiseElevation) {
+        steps += 1.0;
+    }
+    if actions.just_pressed(Action::LowerElevation) {
+        steps -= 1.0;
+    }
+
+    if steps != 0.0 {
+        let mut elevation = query.single_mut();
+        elevation.0 = (elevation.0 + steps * ELEVATION_STEP).clamp(0.0, MAX_ELEVATION);
     }
 }
 
@@ -233,49 +480,100 @@ fn handle_player_aim(
     query.iter_mut().for_each(|(mut transform, parent)| {
         let (parent_transform, aim) = parent_query.get(parent.0).unwrap();
 
-        let parent_facing_direction = parent_transform.local_x();
-        let parent_angle = parent_facing_direction.z.atan2(parent_facing_direction.x);
+        // the tank model faces local x, so this is the world rotation that points it at the aim
+        let aim_rotation = Quat::from_axis_angle(Vec3::Y, (-aim.0.z).atan2(aim.0.x));
 
-        let angle = if aim.0.x == 0.0 {
-            0.0
-        } else {
-            aim.0.z.atan2(aim.0.x)
-        };
-
-        transform.rotation = Quat::from_axis_angle(Vec3::new(0.0, 1.0, 0.0), angle + parent_angle);
+        transform.rotation = parent_transform.rotation.inverse() * aim_rotation;
     });
 }
 
-fn handle_player_shoot_input(
-    mouse: Res<Input<MouseButton>>,
-    mut query: Query<(&Transform, &AimInputDirection, &mut Player)>,
+fn handle_player_fire_input(actions: Res<ActionInput>, mut query: Query<&mut FireInput>) {
+    if actions.just_pressed(Action::Fire) {
+        query.single_mut().0 = true;
+    }
+}
+
+fn handle_ammo_selection(actions: Res<ActionInput>, mut query: Query<&mut SelectedAmmo>) {
+    let selected = [
+        (Action::SelectStandardAmmo, AmmoKind::Standard),
+        (Action::SelectHighExplosiveAmmo, AmmoKind::HighExplosive),
+        (Action::SelectArmorPiercingAmmo, AmmoKind::ArmorPiercing),
+        (Action::SelectBouncingAmmo, AmmoKind::Bouncing),
+    ]
+    .into_iter()
+    .find(|(action, _)| actions.just_pressed(*action));
+
+    if let Some((_, ammo)) = selected {
+        query.single_mut().0 = ammo;
+    }
+}
+
+/// Where the cannon's projectile starts, in front of the tank so that it
+/// does not hit the tank itself, and the velocity that a shell starts with.
+pub fn cannon_launch(translation: Vec3, aim: Vec3, elevation: f32, ammo: AmmoKind) -> (Vec3, Vec3) {
+    let position = translation + aim * PLAYER_SIZE.0.max(PLAYER_SIZE.2);
+    let velocity = (aim * elevation.cos() + Vec3::Y * elevation.sin())
+        * SHELL_SPEED
+        * ammo.stats().speed_factor;
+
+    (position, velocity)
+}
+
+fn handle_player_shoot(
+    mut query: Query<(
+        &Transform,
+        &AimInputDirection,
+        &AimElevation,
+        &SelectedAmmo,
+        &mut FireInput,
+        &mut Player,
+    )>,
     mut commands: Commands,
     bullet_assets: Res<BulletAssets>,
+    mut bullet_pool: ResMut<BulletPool>,
 ) {
-    let (transform, aim, mut player) = query.single_mut();
-
-    if mouse.just_pressed(MouseButton::Left) && player.shoot_cooldown <= 0.1 {
-        let aim = Vec3::new(aim.0.x, 0.0, -aim.0.z);
-        let offset = aim * PLAYER_SIZE.0.max(PLAYER_SIZE.2);
-
-        crate::bullets::spawn_bullet(
-            &mut commands,
-            &bullet_assets,
-            transform.translation + offset,
-            aim,
-            BulletType::Player,
-        );
-        player.shoot_cooldown = SHOOT_COOLDOWN;
+    let (transform, aim, elevation, ammo, mut fire, mut player) = query.single_mut();
+
+    // a press during the cooldown is dropped rather than fired once it is over
+    let fire_pressed = std::mem::replace(&mut fire.0, false);
+
+    if fire_pressed && player.shoot_cooldown <= 0.1 {
+        let (position, velocity) = cannon_launch(transform.translation, aim.0, elevation.0, ammo.0);
+        let stats = ammo.0.stats();
+
+        if elevation.0 > 0.0 {
+            crate::bullets::spawn_shell(
+                &mut commands,
+                &bullet_assets,
+                &mut bullet_pool,
+                position,
+                velocity,
+                BulletType::Player,
+                ammo.0,
+            );
+        } else {
+            crate::bullets::spawn_bullet(
+                &mut commands,
+                &bullet_assets,
+                &mut bullet_pool,
+                position,
+                aim.0,
+                BULLET_SPEED * stats.speed_factor,
+                BulletType::Player,
+                ammo.0,
+            );
+        }
+        player.shoot_cooldown = stats.cooldown;
     }
 }
 
-fn handle_player_hit_recovery(time: Res<Time>, mut query: Query<&mut Player>) {
+fn handle_player_hit_recovery(time: Res<SimulationTime>, mut query: Query<&mut Player>) {
     let mut player = query.single_mut();
 
     player.hit_recovery = 0f32.max(player.hit_recovery - time.delta_seconds());
 }
 
-fn shoot_cooldown(time: Res<Time>, mut query: Query<&mut Player>) {
+fn shoot_cooldown(time: Res<SimulationTime>, mut query: Query<&mut Player>) {
     let mut player = query.single_mut();
 
     player.shoot_cooldown = 0f32.max(player.shoot_cooldown - time.delta_seconds());
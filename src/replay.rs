@@ -0,0 +1,323 @@
+use bevy::prelude::*;
+use heron::PhysicsTime;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::{
+    bullets::AmmoKind,
+    campaign::{play_level, Campaign},
+    game_state::{restart_level, GameState, LoadingAssets},
+    level::{CurrentLevel, Level},
+    player::{
+        AimElevation, AimInputDirection, DrivingMode, FireInput, MovementInputDirection, Player,
+        PlayerSystem, SelectedAmmo,
+    },
+    ron_file::save_ron,
+    simulation::{SimulationRng, SimulationStage, SimulationSystem, SimulationTime},
+};
+
+/// Records the player's input on every tick, and plays back a `ReplayPlayback`
+/// when there is one.
+#[derive(Default)]
+pub struct ReplayPlugin {
+    /// Where the replay of a level is saved when the level is left. `None`
+    /// keeps it in memory only.
+    pub save_path: Option<String>,
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplayRecorder {
+            save_path: self.save_path.clone(),
+            replay: Replay::default(),
+        })
+        .add_system_set(
+            SystemSet::on_enter(GameState::MainMenu).with_system(discard_unstarted_playback),
+        )
+        .add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(start_replay))
+        .add_system_set(SystemSet::on_enter(GameState::Playing).with_system(begin_level))
+        .add_system_set_to_stage(
+            SimulationStage,
+            SystemSet::on_update(GameState::Playing)
+                .before(PlayerSystem::Simulate)
+                // the time scale is needed before the physics steps
+                .with_system(
+                    play_back_time_scale
+                        .exclusive_system()
+                        .at_start()
+                        .before(SimulationSystem::StepPhysics),
+                )
+                .with_system(play_back_input.label(ReplaySystem::Playback))
+                .with_system(record_input.after(ReplaySystem::Playback)),
+        )
+        .add_system_set(SystemSet::on_exit(GameState::Playing).with_system(end_level));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+enum ReplaySystem {
+    Playback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TickInput {
+    pub movement: Vec3,
+    pub aim: Vec3,
+    pub fire: bool,
+    pub elevation: f32,
+    pub ammo: AmmoKind,
+    pub driving_mode: DrivingMode,
+    /// The simulation's time scale, which can be changed by `TimeControlPlugin`.
+    pub time_scale: f32,
+}
+
+impl Default for TickInput {
+    fn default() -> Self {
+        Self {
+            movement: Vec3::ZERO,
+            aim: Vec3::ZERO,
+            fire: false,
+            elevation: 0.0,
+            ammo: AmmoKind::default(),
+            driving_mode: DrivingMode::default(),
+            time_scale: 1.0,
+        }
+    }
+}
+
+/// Everything needed to simulate a level again the way it was played.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    /// Asset path of the level, if it was loaded from a file.
+    pub level: Option<String>,
+    pub seed: u64,
+    /// Input of every tick, run-length encoded as `(ticks, input)`.
+    inputs: Vec<(u32, TickInput)>,
+}
+
+impl Replay {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &str) {
+        save_ron(path, self);
+    }
+
+    pub fn push(&mut self, input: TickInput) {
+        match self.inputs.last_mut() {
+            Some((ticks, last)) if *last == input => *ticks += 1,
+            _ => self.inputs.push((1, input)),
+        }
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = TickInput> + '_ {
+        self.inputs
+            .iter()
+            .flat_map(|(ticks, input)| (0..*ticks).map(move |_| *input))
+    }
+}
+
+pub struct ReplayRecorder {
+    save_path: Option<String>,
+    replay: Replay,
+}
+
+impl ReplayRecorder {
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+}
+
+/// A replay to play back on the next level that is started. Once the level is
+/// left, the game goes back to reading the player's input.
+pub struct ReplayPlayback {
+    replay: Replay,
+    inputs: Vec<TickInput>,
+    tick: usize,
+    /// The player's own driving mode, which is put back once the level is left.
+    driving_mode: DrivingMode,
+}
+
+impl ReplayPlayback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            inputs: replay.inputs().collect(),
+            replay,
+            tick: 0,
+            driving_mode: DrivingMode::default(),
+        }
+    }
+}
+
+/// Starts the level of a replay that was passed in from the main menu, e.g.
+/// when the game is launched to play back a replay. This is only tried once,
+/// so a level that fails to load does not bring the game back here over and over.
+fn start_replay(
+    playback: Option<Res<ReplayPlayback>>,
+    asset_server: Res<AssetServer>,
+    mut campaign: ResMut<Campaign>,
+    mut current_level: ResMut<CurrentLevel>,
+    mut loading: ResMut<LoadingAssets>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let path = match playback
+        .as_ref()
+        .filter(|playback| playback.is_added())
+        .and_then(|playback| playback.replay.level.as_ref())
+    {
+        Some(path) => path,
+        None => return,
+    };
+
+    let level: Handle<Level> = asset_server.load(path.as_str());
+
+    match campaign.levels.iter().position(|handle| *handle == level) {
+        Some(index) => play_level(index, &mut campaign, &mut current_level, &mut state),
+        None => {
+            loading.0.push(level.clone_untyped());
+            current_level.0 = level;
+            restart_level(&mut state);
+        }
+    }
+}
+
+/// A replay whose level failed to load would otherwise be played back on the
+/// next level that is started.
+fn discard_unstarted_playback(mut commands: Commands, playback: Option<Res<ReplayPlayback>>) {
+    if playback.map_or(false, |playback| !playback.is_added()) {
+        commands.remove_resource::<ReplayPlayback>();
+    }
+}
+
+fn begin_level(
+    asset_server: Res<AssetServer>,
+    current_level: Res<CurrentLevel>,
+    driving_mode: Res<DrivingMode>,
+    playback: Option<ResMut<ReplayPlayback>>,
+    mut rng: ResMut<SimulationRng>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let seed = match playback {
+        Some(mut playback) => {
+            playback.tick = 0;
+            playback.driving_mode = *driving_mode;
+            playback.replay.seed
+        }
+        None => rand::random(),
+    };
+
+    rng.reseed(seed);
+    recorder.replay = Replay {
+        level: asset_server
+            .get_handle_path(&current_level.0)
+            .map(|path| path.path().to_string_lossy().into_owned()),
+        seed,
+        inputs: Vec::new(),
+    };
+}
+
+fn play_back_time_scale(
+    playback: Option<Res<ReplayPlayback>>,
+    mut simulation_time: ResMut<SimulationTime>,
+    mut physics_time: ResMut<PhysicsTime>,
+) {
+    let input = match playback.and_then(|playback| playback.inputs.get(playback.tick).copied()) {
+        Some(input) => input,
+        None => return,
+    };
+
+    simulation_time.set_scale(input.time_scale);
+    physics_time.set_scale(input.time_scale);
+}
+
+fn play_back_input(
+    playback: Option<ResMut<ReplayPlayback>>,
+    simulation_time: Res<SimulationTime>,
+    mut driving_mode: ResMut<DrivingMode>,
+    mut query: Query<
+        (
+            &mut MovementInputDirection,
+            &mut AimInputDirection,
+            &mut FireInput,
+            &mut AimElevation,
+            &mut SelectedAmmo,
+        ),
+        With<Player>,
+    >,
+) {
+    let mut playback = match playback {
+        Some(playback) => playback,
+        None => return,
+    };
+    let (mut movement, mut aim, mut fire, mut elevation, mut ammo) = query.single_mut();
+
+    // once the replay runs out, the tank stops and holds its aim
+    let input = playback
+        .inputs
+        .get(playback.tick)
+        .copied()
+        .unwrap_or(TickInput {
+            aim: aim.0,
+            elevation: elevation.0,
+            ammo: ammo.0,
+            driving_mode: *driving_mode,
+            time_scale: simulation_time.scale(),
+            ..Default::default()
+        });
+    playback.tick += 1;
+
+    movement.0 = input.movement;
+    aim.0 = input.aim;
+    fire.0 = input.fire;
+    elevation.0 = input.elevation;
+    ammo.0 = input.ammo;
+    if *driving_mode != input.driving_mode {
+        *driving_mode = input.driving_mode;
+    }
+}
+
+fn record_input(
+    mut recorder: ResMut<ReplayRecorder>,
+    driving_mode: Res<DrivingMode>,
+    simulation_time: Res<SimulationTime>,
+    query: Query<
+        (
+            &MovementInputDirection,
+            &AimInputDirection,
+            &FireInput,
+            &AimElevation,
+            &SelectedAmmo,
+        ),
+        With<Player>,
+    >,
+) {
+    let (movement, aim, fire, elevation, ammo) = query.single();
+
+    recorder.replay.push(TickInput {
+        movement: movement.0,
+        aim: aim.0,
+        fire: fire.0,
+        elevation: elevation.0,
+        ammo: ammo.0,
+        driving_mode: *driving_mode,
+        time_scale: simulation_time.scale(),
+    });
+}
+
+fn end_level(
+    mut commands: Commands,
+    recorder: Res<ReplayRecorder>,
+    playback: Option<Res<ReplayPlayback>>,
+    mut driving_mode: ResMut<DrivingMode>,
+) {
+    if let Some(path) = &recorder.save_path {
+        recorder.replay.save(path);
+    }
+
+    if let Some(playback) = playback {
+        *driving_mode = playback.driving_mode;
+    }
+
+    commands.remove_resource::<ReplayPlayback>();
+}
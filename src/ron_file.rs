@@ -0,0 +1,15 @@
+use bevy::prelude::*;
+use serde::Serialize;
+use std::fs;
+
+/// Writes `value` to `path` as pretty RON. Failing to save is not worth
+/// stopping the game for, so it is only logged.
+pub fn save_ron<T: Serialize>(path: &str, value: &T) {
+    let result = ron::ser::to_string_pretty(value, Default::default())
+        .map_err(|err| err.to_string())
+        .and_then(|content| fs::write(path, content).map_err(|err| err.to_string()));
+
+    if let Err(err) = result {
+        warn!("Could not save {}: {}", path, err);
+    }
+}
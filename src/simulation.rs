@@ -0,0 +1,228 @@
+use bevy::{
+    app::Events,
+    ecs::{schedule::ShouldRun, system::Resource},
+    prelude::*,
+};
+use heron::{PhysicsSteps, PhysicsTime};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::time::Duration;
+
+/// Requires `GamePhysicsPlugin`, whose physics steps are run by `SimulationStage`.
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        let physics_steps = app
+            .world
+            .get_resource_or_insert_with(SimulationTime::default)
+            .physics_steps();
+
+        // It is single threaded so that systems always run in the same order, which keeps
+        // the simulation reproducible.
+        app.insert_resource(physics_steps)
+            .init_resource::<SimulationRng>()
+            .add_stage_after(
+                CoreStage::PostUpdate,
+                SimulationStage,
+                SystemStage::single_threaded()
+                    .with_run_criteria(run_owed_ticks)
+                    .with_system(
+                        step_physics
+                            .exclusive_system()
+                            .at_start()
+                            .label(SimulationSystem::StepPhysics),
+                    ),
+            );
+    }
+}
+
+const DEFAULT_TICK_RATE: f32 = 60.0;
+/// Ticks that are owed beyond this in a single update are dropped, so that a
+/// slow frame does not make the next one even slower. The game only slows
+/// down below 15 frames per second.
+const MAX_TICKS_PER_UPDATE: u32 = 4;
+
+/// Stage that runs once for every tick owed for the real time that passed,
+/// which can be several times in an update or not at all. Every tick first
+/// steps the physics, and then runs the gameplay systems, so they behave the
+/// same at any frame rate.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub struct SimulationStage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+pub enum SimulationSystem {
+    StepPhysics,
+}
+
+/// Heron's systems, which `SimulationStage` runs once per tick, instead of
+/// heron stepping on its own once per frame.
+pub struct PhysicsSchedule(pub Schedule);
+
+pub trait SimulationEvents {
+    /// Like `App::add_event`, except that the events are kept for a tick
+    /// instead of a frame, so that they are not missed by an update without ticks.
+    fn add_simulation_event<T: Resource>(&mut self) -> &mut Self;
+}
+
+impl SimulationEvents for App {
+    fn add_simulation_event<T: Resource>(&mut self) -> &mut Self {
+        self.init_resource::<Events<T>>()
+            .add_system_to_stage(SimulationStage, Events::<T>::update_system)
+    }
+}
+
+/// How much time passes in the game during a tick of `SimulationStage`.
+/// Gameplay systems read this instead of `Time`.
+///
+/// The time scale stretches every tick instead of changing the tick rate, so
+/// it has to be kept in sync with heron's `PhysicsTime`.
+pub struct SimulationTime {
+    delta_seconds: f32,
+    scale: f32,
+    /// Real time that passes during an update, instead of measuring it, e.g. for tests.
+    frame_delta_seconds: Option<f32>,
+    /// Real time that passed, but has not been ticked yet.
+    owed_seconds: f32,
+}
+
+impl Default for SimulationTime {
+    fn default() -> Self {
+        Self::from_tick_rate(DEFAULT_TICK_RATE)
+    }
+}
+
+impl SimulationTime {
+    pub fn from_tick_rate(ticks_per_second: f32) -> Self {
+        Self {
+            delta_seconds: 1.0 / ticks_per_second,
+            scale: 1.0,
+            frame_delta_seconds: None,
+            owed_seconds: 0.0,
+        }
+    }
+
+    /// Ticks once on every update, advancing the game by `delta_seconds`.
+    pub fn fixed(delta_seconds: f32) -> Self {
+        Self {
+            delta_seconds,
+            scale: 1.0,
+            frame_delta_seconds: Some(delta_seconds),
+            owed_seconds: 0.0,
+        }
+    }
+
+    /// Pretends that every update takes `frame_delta_seconds` of real time, so
+    /// the game ticks as if it ran at that frame rate.
+    pub fn with_frame_delta(mut self, frame_delta_seconds: f32) -> Self {
+        self.frame_delta_seconds = Some(frame_delta_seconds);
+        self
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta_seconds * self.scale
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    fn physics_steps(&self) -> PhysicsSteps {
+        PhysicsSteps::every_frame(Duration::from_secs_f32(self.delta_seconds))
+    }
+}
+
+/// Random numbers for the gameplay systems. The same seed always gives the
+/// same numbers, so a level can be simulated again exactly, e.g. by a replay.
+pub struct SimulationRng {
+    seed: u64,
+    rng: ChaCha8Rng,
+}
+
+impl Default for SimulationRng {
+    fn default() -> Self {
+        Self::from_seed(0)
+    }
+}
+
+impl SimulationRng {
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            seed,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        *self = Self::from_seed(seed);
+    }
+}
+
+impl RngCore for SimulationRng {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.rng.try_fill_bytes(dest)
+    }
+}
+
+/// Checked again after every tick, until no more ticks are owed for this update.
+fn run_owed_ticks(
+    time: Res<Time>,
+    physics_time: Res<PhysicsTime>,
+    mut simulation_time: ResMut<SimulationTime>,
+    // `None` until the first check of an update
+    mut ticks_this_update: Local<Option<u32>>,
+) -> ShouldRun {
+    // while paused or frozen, no time is owed, so the game does not rush to catch up after
+    if physics_time.scale() <= 0.0 {
+        simulation_time.owed_seconds = 0.0;
+        *ticks_this_update = None;
+        return ShouldRun::No;
+    }
+
+    let ticks = match *ticks_this_update {
+        Some(ticks) => ticks,
+        None => {
+            simulation_time.owed_seconds += simulation_time
+                .frame_delta_seconds
+                .unwrap_or_else(|| time.delta_seconds());
+            0
+        }
+    };
+
+    if simulation_time.owed_seconds < simulation_time.delta_seconds {
+        *ticks_this_update = None;
+        ShouldRun::No
+    } else if ticks == MAX_TICKS_PER_UPDATE {
+        simulation_time.owed_seconds = 0.0;
+        *ticks_this_update = None;
+        ShouldRun::No
+    } else {
+        simulation_time.owed_seconds -= simulation_time.delta_seconds;
+        *ticks_this_update = Some(ticks + 1);
+        ShouldRun::YesAndCheckAgain
+    }
+}
+
+fn step_physics(world: &mut World) {
+    world.resource_scope(|world, mut physics: Mut<PhysicsSchedule>| physics.0.run_once(world));
+}
@@ -1,41 +1,74 @@
 use bevy::prelude::*;
 use heron::prelude::*;
 
-use crate::game_layer::GameLayer;
+use crate::{
+    game_layer::GameLayer,
+    game_state::{GameState, LevelEntity, LevelSetup, LoadingAssets},
+    level::{CurrentLevel, Level},
+    rendering,
+};
 
 pub struct TerrainPlugin;
 
+/// Height of the top of the ground's collision shape.
+pub const GROUND_HEIGHT: f32 = 1.0;
+
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_terrain);
+        app.init_resource::<TerrainAssets>()
+            .add_startup_system(load_terrain_assets.with_run_criteria(rendering))
+            .add_system_set(
+                SystemSet::on_enter(GameState::Playing)
+                    .with_system(setup_terrain.label(LevelSetup::Terrain)),
+            );
     }
 }
 
-pub const TERRAIN_SIZE: f32 = 256.0;
+#[derive(Default)]
+struct TerrainAssets {
+    grass: Handle<Image>,
+}
+
+fn load_terrain_assets(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut loading: ResMut<LoadingAssets>,
+) {
+    let grass = asset_server.load("grass.png");
+    loading.0.push(grass.clone_untyped());
+    commands.insert_resource(TerrainAssets { grass });
+}
 
 fn setup_terrain(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    asset_server: Res<AssetServer>,
+    assets: Res<TerrainAssets>,
+    levels: Res<Assets<Level>>,
+    current_level: Res<CurrentLevel>,
 ) {
-    let plane = shape::Plane { size: TERRAIN_SIZE };
+    let terrain_size = levels
+        .get(&current_level.0)
+        .expect("level is not loaded")
+        .arena_size;
 
-    let texture = StandardMaterial {
+    let mesh = meshes.add(shape::Plane { size: terrain_size }.into());
+    let material = materials.add(StandardMaterial {
         base_color: Color::WHITE,
-        base_color_texture: Some(asset_server.load("grass.png")),
+        base_color_texture: Some(assets.grass.clone()),
         ..Default::default()
-    };
+    });
 
     commands
         .spawn_bundle(PbrBundle {
-            mesh: meshes.add(plane.into()),
-            material: materials.add(texture),
+            mesh,
+            material,
             ..Default::default()
         })
         .insert(RigidBody::Static)
+        .insert(LevelEntity)
         .insert(CollisionShape::Cuboid {
-            half_extends: Vec3::new(TERRAIN_SIZE / 2.0, 1.0, TERRAIN_SIZE / 2.0),
+            half_extends: Vec3::new(terrain_size / 2.0, GROUND_HEIGHT, terrain_size / 2.0),
             border_radius: None,
         })
         .insert(
@@ -48,13 +81,14 @@ fn setup_terrain(
     commands
         .spawn()
         .insert(Transform {
-            translation: Vec3::new(0.0, 0.0, TERRAIN_SIZE / 2.0),
+            translation: Vec3::new(0.0, 0.0, terrain_size / 2.0),
             ..Default::default()
         })
         .insert(GlobalTransform::default())
         .insert(RigidBody::Static)
+        .insert(LevelEntity)
         .insert(CollisionShape::Cuboid {
-            half_extends: Vec3::new(TERRAIN_SIZE / 2.0, 100.0, 1.0),
+            half_extends: Vec3::new(terrain_size / 2.0, 100.0, 1.0),
             border_radius: None,
         })
         .insert(
@@ -65,13 +99,14 @@ fn setup_terrain(
     commands
         .spawn()
         .insert(Transform {
-            translation: Vec3::new(0.0, 0.0, -TERRAIN_SIZE / 2.0),
+            translation: Vec3::new(0.0, 0.0, -terrain_size / 2.0),
             ..Default::default()
         })
         .insert(GlobalTransform::default())
         .insert(RigidBody::Static)
+        .insert(LevelEntity)
         .insert(CollisionShape::Cuboid {
-            half_extends: Vec3::new(TERRAIN_SIZE / 2.0, 100.0, 1.0),
+            half_extends: Vec3::new(terrain_size / 2.0, 100.0, 1.0),
             border_radius: None,
         })
         .insert(
@@ -82,13 +117,14 @@ fn setup_terrain(
     commands
         .spawn()
         .insert(Transform {
-            translation: Vec3::new(TERRAIN_SIZE / 2.0, 0.0, 0.0),
+            translation: Vec3::new(terrain_size / 2.0, 0.0, 0.0),
             ..Default::default()
         })
         .insert(GlobalTransform::default())
         .insert(RigidBody::Static)
+        .insert(LevelEntity)
         .insert(CollisionShape::Cuboid {
-            half_extends: Vec3::new(1.0, 100.0, TERRAIN_SIZE / 2.0),
+            half_extends: Vec3::new(1.0, 100.0, terrain_size / 2.0),
             border_radius: None,
         })
         .insert(
@@ -99,13 +135,14 @@ fn setup_terrain(
     commands
         .spawn()
         .insert(Transform {
-            translation: Vec3::new(-TERRAIN_SIZE / 2.0, 0.0, 0.0),
+            translation: Vec3::new(-terrain_size / 2.0, 0.0, 0.0),
             ..Default::default()
         })
         .insert(GlobalTransform::default())
         .insert(RigidBody::Static)
+        .insert(LevelEntity)
         .insert(CollisionShape::Cuboid {
-            half_extends: Vec3::new(1.0, 100.0, TERRAIN_SIZE / 2.0),
+            half_extends: Vec3::new(1.0, 100.0, terrain_size / 2.0),
             border_radius: None,
         })
         .insert(
@@ -0,0 +1,106 @@
+use bevy::prelude::*;
+use heron::PhysicsTime;
+
+use crate::{
+    actions::{Action, ActionInput},
+    game_state::GameState,
+    simulation::{SimulationStage, SimulationTime},
+};
+
+/// Debug keys to slow down, speed up, freeze and single-step the simulation,
+/// including the physics.
+pub struct TimeControlPlugin;
+
+impl Plugin for TimeControlPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TimeControl>()
+            .add_system_set(SystemSet::on_enter(GameState::Loading).with_system(reset_time_control))
+            .add_system_set(
+                SystemSet::on_update(GameState::Playing).with_system(handle_time_control_input),
+            )
+            .add_system_to_stage(SimulationStage, finish_single_step);
+    }
+}
+
+const MIN_SCALE: f32 = 0.25;
+const MAX_SCALE: f32 = 8.0;
+
+pub struct TimeControl {
+    scale: f32,
+    frozen: bool,
+    /// A single tick was requested while frozen, and has not run yet.
+    stepping: bool,
+}
+
+impl Default for TimeControl {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            frozen: false,
+            stepping: false,
+        }
+    }
+}
+
+impl TimeControl {
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn frozen(&self) -> bool {
+        self.frozen
+    }
+
+    fn physics_scale(&self) -> f32 {
+        if self.frozen && !self.stepping {
+            0.0
+        } else {
+            self.scale
+        }
+    }
+}
+
+/// Every level starts at normal speed, so that restarting a frozen or slowed
+/// down game does not carry that over.
+fn reset_time_control(
+    mut control: ResMut<TimeControl>,
+    mut simulation_time: ResMut<SimulationTime>,
+    mut physics_time: ResMut<PhysicsTime>,
+) {
+    *control = TimeControl::default();
+    simulation_time.set_scale(control.scale);
+    physics_time.set_scale(control.physics_scale());
+}
+
+fn handle_time_control_input(
+    actions: Res<ActionInput>,
+    mut control: ResMut<TimeControl>,
+    mut simulation_time: ResMut<SimulationTime>,
+    mut physics_time: ResMut<PhysicsTime>,
+) {
+    if actions.just_pressed(Action::SlowDownTime) {
+        control.scale = (control.scale / 2.0).max(MIN_SCALE);
+    }
+    if actions.just_pressed(Action::SpeedUpTime) {
+        control.scale = (control.scale * 2.0).min(MAX_SCALE);
+    }
+    if actions.just_pressed(Action::FreezeTime) {
+        control.frozen = !control.frozen;
+        control.stepping = false;
+    }
+    if actions.just_pressed(Action::StepTime) && control.frozen {
+        control.stepping = true;
+    }
+
+    if control.is_changed() {
+        simulation_time.set_scale(control.scale);
+        physics_time.set_scale(control.physics_scale());
+    }
+}
+
+fn finish_single_step(mut control: ResMut<TimeControl>, mut physics_time: ResMut<PhysicsTime>) {
+    if control.stepping {
+        control.stepping = false;
+        physics_time.set_scale(control.physics_scale());
+    }
+}
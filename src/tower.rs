@@ -1,51 +1,231 @@
 use bevy::prelude::*;
-use heron::prelude::*;
+use heron::{prelude::*, rapier_plugin::PhysicsWorld};
+use rand::Rng;
+use serde::Deserialize;
 
 use crate::{
-    bullets::{BulletAssets, BulletType},
+    bullets::{shell_launch_angle, AmmoKind, BulletAssets, BulletPool, BulletType},
     game_layer::GameLayer,
-    player::Player,
+    game_state::{GameState, LevelEntity, LevelSetup},
+    level::{CurrentLevel, Level, TowerDescriptor},
+    player::{wrap_angle, Player, PlayerSystem},
+    simulation::{SimulationRng, SimulationStage, SimulationTime},
 };
 
 pub struct TowerPlugin;
 
 impl Plugin for TowerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_towers)
-            .add_system(update_alive_status)
-            .add_system(shoot_bullets);
+        app.add_system_set(
+            SystemSet::on_enter(GameState::Playing).with_system(
+                setup_towers
+                    .label(LevelSetup::Towers)
+                    .after(LevelSetup::Player),
+            ),
+        )
+        .add_system_set_to_stage(
+            SimulationStage,
+            // the player moves first, so that towers lead the velocity it is driving at
+            SystemSet::on_update(GameState::Playing)
+                .label(TowerSystem::Simulate)
+                .after(PlayerSystem::Simulate)
+                .with_system(update_alive_status.before(TowerSystem::Think))
+                .with_system(update_tower_ai.label(TowerSystem::Think))
+                .with_system(shoot_bullets.after(TowerSystem::Think))
+                .with_system(aim_barrels.after(TowerSystem::Think))
+                .with_system(update_head_glow.after(TowerSystem::Think)),
+        );
     }
 }
 
-const CUBE_SIZE: f32 = 4.0;
-const SHOOT_INTERVAL: f32 = 2.0;
-const TOWER_PLAYER_MIN_DISTANCE: f32 = 40.0;
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, SystemLabel)]
+pub enum TowerSystem {
+    Simulate,
+    Think,
+}
+
+pub const CUBE_SIZE: f32 = 4.0;
+/// Largest angle in radians that a tower's aim is off by, at zero accuracy.
+pub const MAX_AIM_ERROR: f32 = 0.3;
+/// How often a lobbed shell's aim is corrected for the longer flight time.
+const LOB_LEAD_ITERATIONS: usize = 3;
+
+/// Seconds between spotting the player and starting to fire at it.
+const ALERT_TIME: f32 = 1.0;
+const IDLE_SCAN_SPEED: f32 = 0.5;
+const TRACKING_SPEED: f32 = 2.5;
+/// The tower fires once its barrel is within this angle of where it aims.
+const TRACKING_TOLERANCE: f32 = 0.1;
+const BARREL_LENGTH: f32 = CUBE_SIZE;
+const BARREL_WIDTH: f32 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TowerKind {
+    Standard,
+    Sniper,
+    Mortar,
+    RapidFire,
+    Shotgun,
+}
+
+impl Default for TowerKind {
+    fn default() -> Self {
+        TowerKind::Standard
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TowerStats {
+    pub range: f32,
+    pub fire_interval: f32,
+    pub projectile_speed: f32,
+    pub burst_count: u32,
+    pub spread: f32,
+    /// From 0.0, which misses by up to `MAX_AIM_ERROR`, to 1.0, which always aims
+    /// at where the player will be when the bullet gets there.
+    pub accuracy: f32,
+    /// Fires shells that arc under gravity instead of straight bullets.
+    pub ballistic: bool,
+}
+
+impl TowerKind {
+    pub fn stats(self) -> TowerStats {
+        match self {
+            TowerKind::Standard => TowerStats {
+                range: 40.0,
+                fire_interval: 2.0,
+                projectile_speed: 12.0,
+                burst_count: 1,
+                spread: 0.0,
+                accuracy: 0.6,
+                ballistic: false,
+            },
+            TowerKind::Sniper => TowerStats {
+                range: 90.0,
+                fire_interval: 4.0,
+                projectile_speed: 36.0,
+                burst_count: 1,
+                spread: 0.0,
+                accuracy: 0.95,
+                ballistic: false,
+            },
+            TowerKind::Mortar => TowerStats {
+                range: 70.0,
+                fire_interval: 5.0,
+                projectile_speed: 28.0,
+                burst_count: 1,
+                spread: 0.0,
+                accuracy: 0.5,
+                ballistic: true,
+            },
+            TowerKind::RapidFire => TowerStats {
+                range: 35.0,
+                fire_interval: 0.4,
+                projectile_speed: 16.0,
+                burst_count: 1,
+                spread: 0.0,
+                accuracy: 0.4,
+                ballistic: false,
+            },
+            TowerKind::Shotgun => TowerStats {
+                range: 25.0,
+                fire_interval: 3.0,
+                projectile_speed: 12.0,
+                burst_count: 5,
+                spread: 0.8,
+                accuracy: 0.7,
+                ballistic: false,
+            },
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            TowerKind::Standard => Color::WHITE,
+            TowerKind::Sniper => Color::rgb(0.5, 0.7, 1.0),
+            TowerKind::Mortar => Color::rgb(0.6, 0.45, 0.3),
+            TowerKind::RapidFire => Color::rgb(1.0, 0.9, 0.4),
+            TowerKind::Shotgun => Color::rgb(1.0, 0.6, 0.3),
+        }
+    }
+}
 
 #[derive(Component)]
 pub struct TowerHead {
     pub alive: bool,
+    pub kind: TowerKind,
+    pub accuracy: f32,
     initial_y: f32,
-    shoot_time: f32,
+}
+
+/// What a tower is doing, which its head shows by glowing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TowerAiState {
+    Idle,
+    Alert { time_left: f32 },
+    Tracking,
+    Reloading { time_left: f32 },
+}
+
+impl TowerAiState {
+    fn glow(self) -> Color {
+        match self {
+            TowerAiState::Idle => Color::BLACK,
+            TowerAiState::Alert { .. } => Color::rgb(0.6, 0.5, 0.0),
+            TowerAiState::Tracking => Color::rgb(0.8, 0.1, 0.0),
+            TowerAiState::Reloading { .. } => Color::rgb(0.25, 0.05, 0.0),
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct TowerAi {
+    pub state: TowerAiState,
+    /// Direction that the barrel faces, as an angle around +y from +x.
+    yaw: f32,
+    /// Set on the tick that the tower fires.
+    fire: bool,
+}
+
+#[derive(Component)]
+struct TowerBarrel;
+
+struct TowerMeshes {
+    cube: Handle<Mesh>,
+    barrel: Handle<Mesh>,
+    barrel_material: Handle<StandardMaterial>,
 }
 
 fn spawn_tower(
     commands: &mut Commands,
-    position: Vec3,
-    cube: Handle<Mesh>,
+    tower: &TowerDescriptor,
+    meshes: &TowerMeshes,
     cube_material: Handle<StandardMaterial>,
+    head_material: Handle<StandardMaterial>,
 ) {
+    let cube = meshes.cube.clone();
+
+    let total_height = tower.height;
+
     commands
         .spawn_bundle((
             Transform {
-                translation: position,
+                translation: tower.position,
                 ..Default::default()
             },
             GlobalTransform::default(),
         ))
+        .insert(LevelEntity)
         .with_children(|parent| {
-            let total_height = 6;
             (0..total_height)
-                .map(|i| (i, cube.clone(), cube_material.clone()))
+                .map(|i| {
+                    let material = if i == total_height - 1 {
+                        head_material.clone()
+                    } else {
+                        cube_material.clone()
+                    };
+                    (i, cube.clone(), material)
+                })
                 .for_each({
                     move |(i, cube, cube_material)| {
                         let y = (i as f32) * CUBE_SIZE + 0.5;
@@ -79,7 +259,6 @@ fn spawn_tower(
                                 restitution: 0.0,
                                 density: 1500.0,
                                 friction: 1.0,
-                                ..Default::default()
                             })
                             .insert(
                                 CollisionLayers::none()
@@ -93,11 +272,28 @@ fn spawn_tower(
                             );
 
                         if i == total_height - 1 {
-                            section.insert(TowerHead {
-                                alive: true,
-                                initial_y: y,
-                                shoot_time: SHOOT_INTERVAL,
-                            });
+                            section
+                                .insert(TowerHead {
+                                    alive: true,
+                                    kind: tower.kind,
+                                    accuracy: tower
+                                        .accuracy
+                                        .unwrap_or_else(|| tower.kind.stats().accuracy),
+                                    initial_y: y,
+                                })
+                                .insert(TowerAi {
+                                    state: TowerAiState::Idle,
+                                    yaw: 0.0,
+                                    fire: false,
+                                })
+                                .with_children(|head| {
+                                    head.spawn_bundle(PbrBundle {
+                                        mesh: meshes.barrel.clone(),
+                                        material: meshes.barrel_material.clone(),
+                                        ..Default::default()
+                                    })
+                                    .insert(TowerBarrel);
+                                });
                         }
                     }
                 });
@@ -108,29 +304,40 @@ fn setup_towers(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    levels: Res<Assets<Level>>,
+    current_level: Res<CurrentLevel>,
 ) {
-    let cube = meshes.add(shape::Cube { size: CUBE_SIZE }.into());
-    let cube_material = materials.add(Color::WHITE.into());
-
-    [
-        Vec3::new(10.0, 0.0, 10.0),
-        Vec3::new(40.0, 0.0, 10.0),
-        Vec3::new(70.0, 0.0, 40.0),
-        Vec3::new(-20.0, 0.0, -80.0),
-        Vec3::new(-30.0, 0.0, -60.0),
-        Vec3::new(-20.0, 0.0, -60.0),
-        Vec3::new(-30.0, 0.0, -80.0),
-        Vec3::new(-30.0, 0.0, 110.0),
-        Vec3::new(-40.0, 0.0, 80.0),
-        Vec3::new(-45.0, 0.0, 70.0),
-        Vec3::new(80.0, 0.0, -30.0),
-        Vec3::new(-60.0, 0.0, -90.0),
-        Vec3::new(100.0, 0.0, 95.0),
-        Vec3::new(-95.0, 0.0, -10.0),
-    ]
-    .into_iter()
-    .for_each(|pos| {
-        spawn_tower(&mut commands, pos, cube.clone(), cube_material.clone());
+    let level = levels.get(&current_level.0).expect("level is not loaded");
+    let mut add_material = |color: Color| materials.add(color.into());
+
+    let tower_meshes = TowerMeshes {
+        cube: meshes.add(shape::Cube { size: CUBE_SIZE }.into()),
+        // sticks out of the side of the head, turning around its center
+        barrel: meshes.add(
+            shape::Box {
+                min_x: 0.0,
+                max_x: BARREL_LENGTH,
+                min_y: -BARREL_WIDTH / 2.0,
+                max_y: BARREL_WIDTH / 2.0,
+                min_z: -BARREL_WIDTH / 2.0,
+                max_z: BARREL_WIDTH / 2.0,
+            }
+            .into(),
+        ),
+        barrel_material: add_material(Color::DARK_GRAY),
+    };
+
+    level.towers.iter().for_each(|tower| {
+        // the head gets its own material, so that it can glow on its own
+        let cube_material = add_material(tower.kind.color());
+        let head_material = add_material(tower.kind.color());
+        spawn_tower(
+            &mut commands,
+            tower,
+            &tower_meshes,
+            cube_material,
+            head_material,
+        );
     });
 }
 
@@ -142,43 +349,327 @@ fn update_alive_status(
         |(mut head, transform, mut material)| {
             if transform.translation.y < head.initial_y - CUBE_SIZE * 2.0 {
                 head.alive = false;
+
                 *material = materials.add(Color::GRAY.into());
             }
         },
     );
 }
 
+fn update_tower_ai(
+    time: Res<SimulationTime>,
+    gravity: Res<Gravity>,
+    physics_world: PhysicsWorld,
+    mut query: Query<(&TowerHead, &mut TowerAi, &GlobalTransform, &Parent)>,
+    player_query: Query<(&GlobalTransform, &Velocity), With<Player>>,
+    sections: Query<&Parent>,
+) {
+    let (player_transform, player_velocity) = player_query.single();
+    let delta = time.delta_seconds();
+
+    query
+        .iter_mut()
+        .filter(|(head, _, _, _)| head.alive)
+        .for_each(|(head, mut ai, transform, tower)| {
+            let stats = head.kind.stats();
+            let direction = player_transform.translation - transform.translation;
+            // shells are lobbed over anything in the way
+            let sees_player = direction.length() < stats.range
+                && (stats.ballistic
+                    || has_line_of_sight(
+                        &physics_world,
+                        &sections,
+                        tower.0,
+                        transform.translation,
+                        direction,
+                    ));
+
+            // the barrel turns to where the next shot will go, not where the player is
+            let aim = aim_direction(
+                transform.translation,
+                player_transform.translation,
+                player_velocity,
+                &stats,
+                gravity.vector(),
+            )
+            .unwrap_or(direction);
+            let target_yaw = (-aim.z).atan2(aim.x);
+            let remaining_yaw = wrap_angle(target_yaw - ai.yaw);
+
+            ai.state = match ai.state {
+                TowerAiState::Idle if sees_player => TowerAiState::Alert {
+                    time_left: ALERT_TIME,
+                },
+                TowerAiState::Idle => TowerAiState::Idle,
+                TowerAiState::Alert { .. } | TowerAiState::Tracking if !sees_player => {
+                    TowerAiState::Idle
+                }
+                TowerAiState::Alert { time_left } if time_left > delta => TowerAiState::Alert {
+                    time_left: time_left - delta,
+                },
+                TowerAiState::Alert { .. } => TowerAiState::Tracking,
+                TowerAiState::Tracking if remaining_yaw.abs() < TRACKING_TOLERANCE => {
+                    ai.fire = true;
+                    TowerAiState::Reloading {
+                        time_left: stats.fire_interval,
+                    }
+                }
+                TowerAiState::Tracking => TowerAiState::Tracking,
+                TowerAiState::Reloading { time_left } if time_left > delta => {
+                    TowerAiState::Reloading {
+                        time_left: time_left - delta,
+                    }
+                }
+                TowerAiState::Reloading { .. } if sees_player => TowerAiState::Tracking,
+                TowerAiState::Reloading { .. } => TowerAiState::Idle,
+            };
+
+            ai.yaw = if sees_player {
+                let turn = TRACKING_SPEED * delta;
+                wrap_angle(ai.yaw + remaining_yaw.clamp(-turn, turn))
+            } else if ai.state == TowerAiState::Idle {
+                wrap_angle(ai.yaw + IDLE_SCAN_SPEED * delta)
+            } else {
+                ai.yaw
+            };
+        });
+}
+
+/// Whether nothing but the tower itself is between a tower head and a point
+/// `direction` away from it.
+fn has_line_of_sight(
+    physics_world: &PhysicsWorld,
+    sections: &Query<&Parent>,
+    tower: Entity,
+    from: Vec3,
+    direction: Vec3,
+) -> bool {
+    // the ray starts inside the head, so the tower's own cubes are skipped
+    physics_world
+        .ray_cast_with_filter(
+            from,
+            direction,
+            false,
+            CollisionLayers::none()
+                .with_group(GameLayer::Bullet)
+                .with_masks(&[GameLayer::World, GameLayer::Tower]),
+            |entity| {
+                sections
+                    .get(entity)
+                    .map_or(true, |section| section.0 != tower)
+            },
+        )
+        .is_none()
+}
+
 fn shoot_bullets(
-    time: Res<Time>,
     mut commands: Commands,
     bullet_assets: Res<BulletAssets>,
-    mut query: Query<(&mut TowerHead, &GlobalTransform)>,
-    player_query: Query<&GlobalTransform, (With<Player>, Without<TowerHead>)>,
+    mut bullet_pool: ResMut<BulletPool>,
+    gravity: Res<Gravity>,
+    mut rng: ResMut<SimulationRng>,
+    mut query: Query<(&TowerHead, &mut TowerAi, &GlobalTransform)>,
+    player_query: Query<(&GlobalTransform, &Velocity), With<Player>>,
 ) {
-    let player_transform = player_query.single();
+    let (player_transform, player_velocity) = player_query.single();
 
     query
         .iter_mut()
-        .filter(|(head, _)| head.alive)
-        .for_each(|(mut head, transform)| {
-            head.shoot_time -= time.delta_seconds();
+        .filter(|(_, ai, _)| ai.fire)
+        .for_each(|(head, mut ai, transform)| {
+            ai.fire = false;
 
-            if head.shoot_time <= 0.0 {
-                head.shoot_time = SHOOT_INTERVAL;
+            let stats = head.kind.stats();
+            let direction = match aim_direction(
+                transform.translation,
+                player_transform.translation,
+                player_velocity,
+                &stats,
+                gravity.vector(),
+            ) {
+                Some(direction) => direction,
+                // out of reach, so the shell is not wasted
+                None => return,
+            };
 
-                let direction = player_transform.translation - transform.translation;
+            let max_error = (1.0 - head.accuracy.clamp(0.0, 1.0)) * MAX_AIM_ERROR;
+            let error = if max_error > 0.0 {
+                rng.gen_range(-max_error..=max_error)
+            } else {
+                0.0
+            };
+            let direction = Quat::from_rotation_y(error) * direction;
 
-                if direction.length() < TOWER_PLAYER_MIN_DISTANCE {
-                    let offset = Vec3::new(direction.normalize().x, 0.0, direction.normalize().z);
+            burst_directions(direction, &stats).for_each(|direction| {
+                let position = transform.translation + muzzle_offset(direction);
 
+                if stats.ballistic {
+                    crate::bullets::spawn_shell(
+                        &mut commands,
+                        &bullet_assets,
+                        &mut bullet_pool,
+                        position,
+                        direction,
+                        BulletType::Tower,
+                        AmmoKind::Standard,
+                    );
+                } else {
                     crate::bullets::spawn_bullet(
                         &mut commands,
                         &bullet_assets,
-                        transform.translation + (offset * CUBE_SIZE * 1.25),
+                        &mut bullet_pool,
+                        position,
                         direction,
+                        stats.projectile_speed,
                         BulletType::Tower,
+                        AmmoKind::Standard,
                     );
                 }
+            });
+        });
+}
+
+/// Where a tower has to shoot to hit the player. For shells, this is the
+/// velocity that they are launched with, and `None` if the player is out of
+/// reach.
+fn aim_direction(
+    shooter: Vec3,
+    target: Vec3,
+    target_velocity: &Velocity,
+    stats: &TowerStats,
+    gravity: Vec3,
+) -> Option<Vec3> {
+    // the tank drives on the ground, so bumps are not worth leading
+    let target_velocity = Vec3::new(target_velocity.linear.x, 0.0, target_velocity.linear.z);
+
+    if stats.ballistic {
+        lob_velocity(
+            shooter,
+            target,
+            target_velocity,
+            stats.projectile_speed,
+            gravity,
+        )
+    } else {
+        let aim_point = intercept_point(shooter, target, target_velocity, stats.projectile_speed)
+            .unwrap_or(target);
+        Some(aim_point - shooter)
+    }
+}
+
+fn muzzle_offset(direction: Vec3) -> Vec3 {
+    Vec3::new(direction.x, 0.0, direction.z).normalize_or_zero() * CUBE_SIZE * 1.25
+}
+
+/// The velocity to lob a shell at, so that it comes down on a target that
+/// keeps moving at `target_velocity`. `None` if the target is out of reach.
+fn lob_velocity(
+    shooter: Vec3,
+    target: Vec3,
+    target_velocity: Vec3,
+    speed: f32,
+    gravity: Vec3,
+) -> Option<Vec3> {
+    let launch = |aim_point: Vec3| {
+        let start = shooter + muzzle_offset(aim_point - shooter);
+        let offset = aim_point - start;
+        let angle = shell_launch_angle(offset, speed, gravity)?;
+        let horizontal = Vec3::new(offset.x, 0.0, offset.z);
+
+        Some((horizontal, angle))
+    };
+
+    // a shell is in the air for much longer than a bullet, so where the target will
+    // be is found by refining the aim with the flight time of the previous guess
+    let mut aim_point = target;
+    for _ in 0..LOB_LEAD_ITERATIONS {
+        let (horizontal, angle) = launch(aim_point)?;
+        let flight_time = horizontal.length() / (speed * angle.cos());
+        aim_point = target + target_velocity * flight_time;
+    }
+
+    let (horizontal, angle) = launch(aim_point)?;
+    Some((horizontal.normalize() * angle.cos() + Vec3::Y * angle.sin()) * speed)
+}
+
+/// Points the barrels along the tower's aim, however the head has been knocked
+/// around.
+fn aim_barrels(
+    heads: Query<(&TowerAi, &GlobalTransform, &Children)>,
+    mut barrels: Query<&mut Transform, With<TowerBarrel>>,
+) {
+    heads.iter().for_each(|(ai, head_transform, children)| {
+        children.iter().for_each(|child| {
+            if let Ok(mut transform) = barrels.get_mut(*child) {
+                transform.rotation =
+                    head_transform.rotation.inverse() * Quat::from_rotation_y(ai.yaw);
+            }
+        });
+    });
+}
+
+fn update_head_glow(
+    query: Query<(&TowerHead, &TowerAi, &Handle<StandardMaterial>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    query
+        .iter()
+        .filter(|(head, _, _)| head.alive)
+        .for_each(|(_, ai, handle)| {
+            let glow = ai.state.glow();
+
+            // only touch the material when the glow changes, so it is not re-uploaded every tick
+            if materials.get(handle).map(|material| material.emissive) != Some(glow) {
+                if let Some(material) = materials.get_mut(handle) {
+                    material.emissive = glow;
+                }
             }
         });
 }
+
+/// Where a bullet fired from `shooter` at `speed` meets a target moving at a
+/// constant velocity, if it can catch up with it at all.
+pub fn intercept_point(
+    shooter: Vec3,
+    target: Vec3,
+    target_velocity: Vec3,
+    speed: f32,
+) -> Option<Vec3> {
+    let offset = target - shooter;
+
+    // solves |offset + target_velocity * t| = speed * t for the earliest t > 0
+    let a = target_velocity.length_squared() - speed * speed;
+    let b = 2.0 * offset.dot(target_velocity);
+    let c = offset.length_squared();
+
+    let time = if a.abs() < f32::EPSILON {
+        (b.abs() > f32::EPSILON).then(|| -c / b)?
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let root = discriminant.sqrt();
+        let (t1, t2) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+        match (t1 > 0.0, t2 > 0.0) {
+            (true, true) => t1.min(t2),
+            (true, false) => t1,
+            (false, true) => t2,
+            (false, false) => return None,
+        }
+    };
+
+    (time > 0.0).then(|| target + target_velocity * time)
+}
+
+fn burst_directions(direction: Vec3, stats: &TowerStats) -> impl Iterator<Item = Vec3> {
+    let count = stats.burst_count.max(1);
+    let (first, step) = if count > 1 {
+        (-stats.spread / 2.0, stats.spread / (count - 1) as f32)
+    } else {
+        (0.0, 0.0)
+    };
+
+    (0..count).map(move |i| Quat::from_rotation_y(first + step * i as f32) * direction)
+}
@@ -0,0 +1,1050 @@
+use bevy::{app::PluginGroupBuilder, asset::AssetPlugin, prelude::*, transform::TransformPlugin};
+use heron::{Gravity, PhysicsTime, Velocity};
+use serde::Deserialize;
+use std::{env, f32::consts::PI, fs, process, thread, time::Duration};
+use tank_game_bevy::{
+    actions::{Binding, InputBindings},
+    bullets::{shell_impact_point, AmmoKind, BULLET_SIZE_RADIUS},
+    level::TowerDescriptor,
+    player::{cannon_launch, wrap_angle, AimElevation, DrivingMode, PlayerSystem, SHOOT_COOLDOWN},
+    simulation::SimulationStage,
+    terrain::GROUND_HEIGHT,
+    tower::{intercept_point, TowerAi, TowerAiState, CUBE_SIZE, MAX_AIM_ERROR},
+    Action, ActionInput, AimInputDirection, BulletPool, BulletType, Campaign, CampaignPlugin,
+    CurrentLevel, GameState, HeadlessPlugins, Level, MovementInputDirection, Player,
+    ReplayPlayback, ReplayPlugin, ReplayRecorder, SimulationRng, SimulationTime, TimeControl,
+    TimeControlPlugin, TowerHead, TowerKind,
+};
+
+const DELTA: f32 = 1.0 / 60.0;
+
+/// Starts a headless game on a level with the player at the origin and the given towers.
+fn start_level(towers: Vec<TowerDescriptor>) -> App {
+    start_level_with(towers, |_| {})
+}
+
+/// Same as `start_level`, with `setup` called on the app before it starts.
+fn start_level_with(towers: Vec<TowerDescriptor>, setup: impl FnOnce(&mut App)) -> App {
+    start_level_with_plugins(towers, |group| group, setup)
+}
+
+/// Same as `start_level_with`, with `plugins` changing `HeadlessPlugins`, e.g.
+/// to disable one of them that `setup` adds again with other settings.
+fn start_level_with_plugins(
+    towers: Vec<TowerDescriptor>,
+    plugins: impl FnOnce(&mut PluginGroupBuilder) -> &mut PluginGroupBuilder,
+    setup: impl FnOnce(&mut App),
+) -> App {
+    let app = load_level_with_plugins(towers, plugins, setup);
+    assert_eq!(*current_state(&app), GameState::Playing);
+    app
+}
+
+/// Same as `start_level_with_plugins`, but the level may already be over by
+/// the time this returns.
+fn load_level_with_plugins(
+    towers: Vec<TowerDescriptor>,
+    plugins: impl FnOnce(&mut PluginGroupBuilder) -> &mut PluginGroupBuilder,
+    setup: impl FnOnce(&mut App),
+) -> App {
+    let mut app = App::new();
+    app.insert_resource(SimulationTime::fixed(DELTA))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(AssetPlugin)
+        .add_plugins_with(HeadlessPlugins, plugins);
+    setup(&mut app);
+
+    // runs the startup systems
+    app.update();
+
+    let level = app
+        .world
+        .get_resource_mut::<Assets<Level>>()
+        .unwrap()
+        .add(Level {
+            name: "Test".to_string(),
+            arena_size: 160.0,
+            player_spawn: Vec3::new(0.0, 3.0, 0.0),
+            towers,
+        });
+    app.world.get_resource_mut::<CurrentLevel>().unwrap().0 = level;
+    app.world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .set(GameState::Loading)
+        .unwrap();
+
+    // the campaign levels are still loaded from disk before the level starts
+    for _ in 0..1000 {
+        if !matches!(
+            current_state(&app),
+            GameState::MainMenu | GameState::Loading
+        ) {
+            break;
+        }
+        app.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    app
+}
+
+/// A tower in a corner of the arena, too far away to shoot at the tank, so
+/// that the level is not won right away.
+fn distant_tower() -> TowerDescriptor {
+    tower(Vec3::new(-70.0, 0.0, -70.0), 2)
+}
+
+/// A tower that never misses, so that the physics noise in where its head
+/// settles cannot decide between a bullet hitting and grazing the tank.
+fn tower(position: Vec3, height: u32) -> TowerDescriptor {
+    TowerDescriptor {
+        position,
+        height,
+        kind: Default::default(),
+        accuracy: Some(1.0),
+    }
+}
+
+fn current_state(app: &App) -> &GameState {
+    app.world
+        .get_resource::<State<GameState>>()
+        .unwrap()
+        .current()
+}
+
+fn run_for(app: &mut App, seconds: f32) {
+    (0..(seconds / DELTA).round() as usize).for_each(|_| app.update());
+}
+
+fn player(app: &mut App) -> &Player {
+    app.world
+        .query::<&Player>()
+        .iter(&app.world)
+        .next()
+        .unwrap()
+}
+
+fn tower_head_y(app: &mut App) -> f32 {
+    app.world
+        .query_filtered::<&GlobalTransform, With<TowerHead>>()
+        .iter(&app.world)
+        .next()
+        .unwrap()
+        .translation
+        .y
+}
+
+fn tower_head_alive(app: &mut App) -> bool {
+    app.world
+        .query::<&TowerHead>()
+        .iter(&app.world)
+        .next()
+        .unwrap()
+        .alive
+}
+
+fn player_bullet_count(app: &mut App) -> usize {
+    app.world
+        .query::<&BulletType>()
+        .iter(&app.world)
+        .filter(|bullet_type| **bullet_type == BulletType::Player)
+        .count()
+}
+
+fn tower_bullet_count(app: &mut App) -> usize {
+    app.world
+        .query::<&BulletType>()
+        .iter(&app.world)
+        .filter(|bullet_type| **bullet_type == BulletType::Tower)
+        .count()
+}
+
+/// The state of the tower standing at `position`.
+fn tower_ai_state(app: &mut App, position: Vec3) -> TowerAiState {
+    app.world
+        .query::<(&TowerAi, &GlobalTransform)>()
+        .iter(&app.world)
+        .find(|(_, transform)| {
+            Vec2::new(transform.translation.x, transform.translation.z)
+                .distance(Vec2::new(position.x, position.z))
+                < CUBE_SIZE
+        })
+        .unwrap()
+        .0
+        .state
+}
+
+fn run_until_tower_fires(app: &mut App) {
+    for _ in 0..(5.0 / DELTA) as usize {
+        if tower_bullet_count(app) > 0 {
+            return;
+        }
+        app.update();
+    }
+    panic!("no tower fired");
+}
+
+/// A bullet that a tower has just fired, with what the tower saw when it fired.
+struct TowerShot {
+    head: Vec3,
+    target: Vec3,
+    target_velocity: Vec3,
+    /// The way the bullet flies, of unit length.
+    direction: Vec3,
+}
+
+fn tower_bullets(app: &mut App) -> Vec<(Entity, Vec3)> {
+    app.world
+        .query::<(Entity, &BulletType, &Transform)>()
+        .iter(&app.world)
+        .filter(|(_, bullet_type, _)| **bullet_type == BulletType::Tower)
+        .map(|(entity, _, transform)| (entity, transform.translation))
+        .collect()
+}
+
+/// Runs until the only tower fires a new bullet, and follows it for a tick to
+/// see which way it flies.
+fn next_tower_shot(app: &mut App) -> TowerShot {
+    for _ in 0..(10.0 / DELTA) as usize {
+        let head = app
+            .world
+            .query_filtered::<&GlobalTransform, With<TowerHead>>()
+            .iter(&app.world)
+            .next()
+            .unwrap()
+            .translation;
+        let (target, target_velocity) = app
+            .world
+            .query_filtered::<(&Transform, &Velocity), With<Player>>()
+            .iter(&app.world)
+            .next()
+            .map(|(transform, velocity)| (transform.translation, velocity.linear))
+            .unwrap();
+        let before = tower_bullets(app);
+        app.update();
+
+        // pooled bullets are only handed out again on a later tick, so a bullet
+        // that was not flying on the previous tick is a new one
+        let new_bullet = tower_bullets(app)
+            .into_iter()
+            .find(|(entity, _)| before.iter().all(|(other, _)| other != entity));
+
+        if let Some((bullet, start)) = new_bullet {
+            app.update();
+            let (_, end) = tower_bullets(app)
+                .into_iter()
+                .find(|(entity, _)| *entity == bullet)
+                .expect("the bullet hit something right away");
+
+            return TowerShot {
+                head,
+                target,
+                target_velocity,
+                direction: (end - start).normalize(),
+            };
+        }
+    }
+    panic!("no tower fired");
+}
+
+impl TowerShot {
+    /// Where a tower with perfect aim would have shot, leading the tank.
+    fn lead(&self) -> Vec3 {
+        let speed = TowerKind::Standard.stats().projectile_speed;
+        // towers do not lead the bumps of the tank
+        let velocity = Vec3::new(self.target_velocity.x, 0.0, self.target_velocity.z);
+        intercept_point(self.head, self.target, velocity, speed).unwrap_or(self.target) - self.head
+    }
+
+    /// How far the bullet turned away from `lead` about the vertical axis, in radians.
+    fn yaw_error(&self) -> f32 {
+        let (shot, lead) = (self.direction, self.lead());
+        wrap_angle(shot.x.atan2(shot.z) - lead.x.atan2(lead.z))
+    }
+}
+
+fn set_player_input(app: &mut App, movement: Vec3, aim: Vec3) {
+    let (mut movement_input, mut aim_input) = app
+        .world
+        .query::<(&mut MovementInputDirection, &mut AimInputDirection)>()
+        .iter_mut(&mut app.world)
+        .next()
+        .unwrap();
+    movement_input.0 = movement;
+    aim_input.0 = aim;
+}
+
+/// Presses the action for a single frame.
+fn press(app: &mut App, action: Action) {
+    let mut actions = app.world.get_resource_mut::<ActionInput>().unwrap();
+    actions.press(action);
+    app.update();
+
+    let mut actions = app.world.get_resource_mut::<ActionInput>().unwrap();
+    actions.release(action);
+}
+
+fn fire(app: &mut App) {
+    press(app, Action::Fire);
+}
+
+fn set_player_elevation(app: &mut App, elevation: f32) {
+    app.world
+        .query_filtered::<&mut AimElevation, With<Player>>()
+        .iter_mut(&mut app.world)
+        .next()
+        .unwrap()
+        .0 = elevation;
+}
+
+fn player_bullet_position(app: &mut App) -> Option<Vec3> {
+    app.world
+        .query::<(&BulletType, &Transform)>()
+        .iter(&app.world)
+        .find(|(bullet_type, _)| **bullet_type == BulletType::Player)
+        .map(|(_, transform)| transform.translation)
+}
+
+#[test]
+fn level_without_towers_is_won_right_away() {
+    let app = load_level_with_plugins(vec![], |group| group, |_| {});
+
+    assert_eq!(*current_state(&app), GameState::Victory);
+}
+
+#[test]
+fn player_bullet_knocks_tower_head_down() {
+    let mut app = start_level(vec![tower(Vec3::new(20.0, 0.0, 0.0), 5)]);
+    run_for(&mut app, 1.0);
+
+    let initial_y = tower_head_y(&mut app);
+    assert!(tower_head_alive(&mut app));
+
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    fire(&mut app);
+    run_for(&mut app, 6.0);
+
+    assert!(tower_head_y(&mut app) < initial_y - CUBE_SIZE * 2.0);
+    assert!(!tower_head_alive(&mut app));
+}
+
+/// What is written to the campaign's progress file.
+#[derive(Deserialize)]
+struct SavedProgress {
+    unlocked: usize,
+}
+
+#[test]
+fn winning_saves_the_campaign_progress() {
+    let path = env::temp_dir().join(format!("tank_game_progress_{}.ron", process::id()));
+    let _ = fs::remove_file(&path);
+    let save_path = path.to_str().unwrap().to_string();
+
+    let mut app = start_level_with_plugins(
+        vec![tower(Vec3::new(20.0, 0.0, 0.0), 5)],
+        |group| group.disable::<CampaignPlugin>(),
+        |app| {
+            app.add_plugin(CampaignPlugin {
+                save_path: Some(save_path),
+            });
+        },
+    );
+    run_for(&mut app, 1.0);
+    assert_eq!(app.world.get_resource::<Campaign>().unwrap().unlocked, 1);
+
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    fire(&mut app);
+    run_for(&mut app, 6.0);
+
+    let saved = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    assert_eq!(*current_state(&app), GameState::Victory);
+    assert_eq!(app.world.get_resource::<Campaign>().unwrap().unlocked, 2);
+    let saved: SavedProgress = ron::from_str(&saved.unwrap()).unwrap();
+    assert_eq!(saved.unlocked, 2);
+}
+
+#[test]
+fn explosions_topple_neighbouring_towers() {
+    let mut app = start_level(vec![
+        tower(Vec3::new(20.0, 0.0, 0.0), 8),
+        tower(Vec3::new(20.0, 0.0, 6.0), 8),
+    ]);
+    run_for(&mut app, 1.0);
+
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    press(&mut app, Action::SelectHighExplosiveAmmo);
+    fire(&mut app);
+    run_for(&mut app, 6.0);
+
+    let standing = app
+        .world
+        .query::<&TowerHead>()
+        .iter(&app.world)
+        .filter(|head| head.alive)
+        .count();
+    assert_eq!(standing, 0);
+}
+
+#[test]
+fn fast_bullets_do_not_pass_through_towers() {
+    let mut app = start_level(vec![tower(Vec3::new(20.0, 0.0, 0.0), 5)]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+
+    // a tick moves the bullet further than the width of a cube
+    app.world
+        .get_resource_mut::<SimulationTime>()
+        .unwrap()
+        .set_scale(16.0);
+    press(&mut app, Action::SelectArmorPiercingAmmo);
+    fire(&mut app);
+
+    let mut last = player_bullet_position(&mut app).unwrap();
+    while let Some(position) = player_bullet_position(&mut app) {
+        last = position;
+        app.update();
+    }
+
+    assert!(last.x < 20.0);
+}
+
+#[test]
+fn tower_bullets_hit_player() {
+    let mut app = start_level(vec![tower(Vec3::new(0.0, 0.0, -20.0), 3)]);
+    let initial_health = player(&mut app).health;
+
+    run_for(&mut app, 5.0);
+
+    assert!(player(&mut app).health < initial_health);
+}
+
+#[test]
+fn tower_bullets_do_not_topple_their_own_tower() {
+    let mut app = start_level(vec![tower(Vec3::new(0.0, 0.0, -12.0), 3)]);
+    run_for(&mut app, 1.0);
+    let initial_y = tower_head_y(&mut app);
+    let initial_health = player(&mut app).health;
+
+    run_for(&mut app, 5.0);
+
+    assert!(player(&mut app).health < initial_health);
+    assert!(tower_head_alive(&mut app));
+    assert!((tower_head_y(&mut app) - initial_y).abs() < 0.1);
+}
+
+#[test]
+fn towers_hold_fire_without_line_of_sight() {
+    let shooter = || TowerDescriptor {
+        kind: TowerKind::Sniper,
+        ..tower(Vec3::new(0.0, 0.0, -60.0), 4)
+    };
+    // out of range of the player, so it never fires itself
+    let blocker = || TowerDescriptor {
+        kind: TowerKind::Shotgun,
+        ..tower(Vec3::new(0.0, 0.0, -30.0), 6)
+    };
+    let position = shooter().position;
+
+    let mut app = start_level(vec![shooter()]);
+    run_for(&mut app, 0.5);
+    assert!(matches!(
+        tower_ai_state(&mut app, position),
+        TowerAiState::Alert { .. }
+    ));
+    run_until_tower_fires(&mut app);
+
+    let mut app = start_level(vec![shooter(), blocker()]);
+    run_for(&mut app, 3.0);
+    assert_eq!(tower_ai_state(&mut app, position), TowerAiState::Idle);
+    assert_eq!(tower_bullet_count(&mut app), 0);
+
+    // shells are lobbed over the cover
+    let mut app = start_level(vec![
+        TowerDescriptor {
+            kind: TowerKind::Mortar,
+            ..shooter()
+        },
+        blocker(),
+    ]);
+    run_until_tower_fires(&mut app);
+}
+
+#[test]
+fn towers_spot_track_and_reload() {
+    let near = tower(Vec3::new(0.0, 0.0, -30.0), 4);
+    let far = tower(Vec3::new(70.0, 0.0, 0.0), 4);
+    let (near_position, far_position) = (near.position, far.position);
+    let mut app = start_level(vec![near, far]);
+
+    run_for(&mut app, 0.1);
+    assert!(matches!(
+        tower_ai_state(&mut app, near_position),
+        TowerAiState::Alert { .. }
+    ));
+
+    run_until_tower_fires(&mut app);
+    assert!(matches!(
+        tower_ai_state(&mut app, near_position),
+        TowerAiState::Reloading { .. }
+    ));
+    assert_eq!(tower_ai_state(&mut app, far_position), TowerAiState::Idle);
+}
+
+#[test]
+fn shotgun_tower_fires_a_burst() {
+    let mut app = start_level(vec![TowerDescriptor {
+        position: Vec3::new(15.0, 0.0, 0.0),
+        height: 3,
+        kind: TowerKind::Shotgun,
+        accuracy: None,
+    }]);
+
+    run_until_tower_fires(&mut app);
+    assert_eq!(
+        tower_bullet_count(&mut app),
+        TowerKind::Shotgun.stats().burst_count as usize
+    );
+}
+
+#[test]
+fn towers_lead_moving_targets() {
+    let shooter = Vec3::ZERO;
+    let target = Vec3::new(0.0, 0.0, -20.0);
+    let velocity = Vec3::new(5.0, 0.0, 0.0);
+    let speed = 12.0;
+
+    let intercept = intercept_point(shooter, target, velocity, speed).unwrap();
+    let time = (intercept - target).length() / velocity.length();
+
+    assert!(intercept.x > 0.0);
+    assert!(((intercept - shooter).length() - speed * time).abs() < 0.01);
+
+    // a bullet can never catch up with a target that runs away faster
+    assert!(intercept_point(shooter, target, Vec3::new(0.0, 0.0, -20.0), speed).is_none());
+}
+
+#[test]
+fn towers_shoot_where_moving_targets_will_be() {
+    let mut app = start_level(vec![tower(Vec3::new(0.0, 0.0, -30.0), 4)]);
+    set_player_input(&mut app, Vec3::new(-1.0, 0.0, 0.0), Vec3::X);
+
+    let shot = next_tower_shot(&mut app);
+    assert!(shot.target_velocity.length() > 3.0);
+
+    let lead = shot.lead();
+    let straight = shot.target - shot.head;
+
+    assert!(shot.direction.angle_between(lead) < 0.02);
+    assert!(shot.direction.angle_between(straight) > 0.2);
+}
+
+#[test]
+fn inaccurate_towers_miss_by_a_bounded_reproducible_amount() {
+    let accuracy = 0.5;
+    let run = |seed: u64| {
+        let mut app = start_level_with(
+            vec![TowerDescriptor {
+                accuracy: Some(accuracy),
+                ..tower(Vec3::new(0.0, 0.0, -30.0), 4)
+            }],
+            |app| {
+                app.insert_resource(SimulationRng::from_seed(seed));
+            },
+        );
+
+        (0..4)
+            .map(|_| next_tower_shot(&mut app).yaw_error())
+            .collect::<Vec<_>>()
+    };
+
+    let errors = run(7);
+    let max_error = (1.0 - accuracy) * MAX_AIM_ERROR;
+
+    assert!(errors.iter().all(|error| error.abs() <= max_error + 0.01));
+    assert!(errors.iter().any(|error| error.abs() > 0.01));
+    assert_eq!(errors, run(7));
+}
+
+#[test]
+fn player_shells_land_where_predicted() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+
+    // the cannon is raised in steps of 5 degrees
+    (0..9).for_each(|_| press(&mut app, Action::RaiseElevation));
+    let elevation = app
+        .world
+        .query_filtered::<&AimElevation, With<Player>>()
+        .iter(&app.world)
+        .next()
+        .unwrap()
+        .0;
+    assert!((elevation - PI / 4.0).abs() < 1e-5);
+
+    let translation = app
+        .world
+        .query_filtered::<&Transform, With<Player>>()
+        .iter(&app.world)
+        .next()
+        .unwrap()
+        .translation;
+    let (position, velocity) = cannon_launch(translation, Vec3::X, elevation, AmmoKind::Standard);
+    let gravity = app.world.get_resource::<Gravity>().unwrap().vector();
+    let impact = shell_impact_point(
+        position,
+        velocity,
+        gravity,
+        GROUND_HEIGHT + BULLET_SIZE_RADIUS,
+    )
+    .unwrap();
+
+    fire(&mut app);
+    let (mut highest, mut last) = (position.y, position);
+    for _ in 0..(10.0 / DELTA) as usize {
+        match player_bullet_position(&mut app) {
+            Some(shell) => {
+                highest = highest.max(shell.y);
+                last = shell;
+            }
+            None => break,
+        }
+        app.update();
+    }
+
+    assert_eq!(player_bullet_count(&mut app), 0);
+    assert!(highest > position.y + 5.0);
+    assert!(Vec2::new(last.x - impact.x, last.z - impact.z).length() < 1.0);
+}
+
+#[test]
+fn mortar_shells_hit_player() {
+    let mut app = start_level(vec![TowerDescriptor {
+        kind: TowerKind::Mortar,
+        ..tower(Vec3::new(0.0, 0.0, -50.0), 3)
+    }]);
+    let initial_health = player(&mut app).health;
+
+    run_until_tower_fires(&mut app);
+    run_for(&mut app, 6.0);
+
+    assert!(player(&mut app).health < initial_health);
+}
+
+#[test]
+fn ammo_kinds_have_their_own_cooldown() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+
+    press(&mut app, Action::SelectHighExplosiveAmmo);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    // still reloading after the standard cooldown
+    run_for(&mut app, SHOOT_COOLDOWN);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    run_for(
+        &mut app,
+        AmmoKind::HighExplosive.stats().cooldown - SHOOT_COOLDOWN,
+    );
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 2);
+}
+
+#[test]
+fn bouncing_shells_bounce_off_the_ground() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    set_player_elevation(&mut app, PI / 6.0);
+
+    press(&mut app, Action::SelectBouncingAmmo);
+    fire(&mut app);
+
+    let mut last_y = player_bullet_position(&mut app).unwrap().y;
+    let (mut falling, mut bounced) = (false, false);
+    for _ in 0..(6.0 / DELTA) as usize {
+        app.update();
+        let y = match player_bullet_position(&mut app) {
+            Some(position) => position.y,
+            None => break,
+        };
+
+        if y < last_y {
+            falling = true;
+        } else if falling && y > last_y {
+            bounced = true;
+            break;
+        }
+        last_y = y;
+    }
+
+    assert!(bounced);
+}
+
+#[test]
+fn bullets_are_reused_from_the_pool() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    press(&mut app, Action::SelectArmorPiercingAmmo);
+
+    // fast enough to reach the wall before the cannon is reloaded
+    fire(&mut app);
+    run_for(&mut app, AmmoKind::ArmorPiercing.stats().cooldown);
+    assert_eq!(player_bullet_count(&mut app), 0);
+    fire(&mut app);
+
+    let stats = app.world.get_resource::<BulletPool>().unwrap().stats();
+    assert_eq!(player_bullet_count(&mut app), 1);
+    assert_eq!(stats.size, 1);
+    assert_eq!(stats.free, 0);
+    assert_eq!(stats.requests, 2);
+    assert!((stats.hit_rate() - 0.5).abs() < f32::EPSILON);
+}
+
+#[test]
+fn cooldown_blocks_repeat_fire() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    run_for(&mut app, SHOOT_COOLDOWN / 2.0);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    run_for(&mut app, SHOOT_COOLDOWN / 2.0);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 2);
+}
+
+/// The linear speed of the tank along the ground, and how fast it turns.
+fn player_motion(app: &mut App) -> (f32, f32) {
+    let velocity = app
+        .world
+        .query_filtered::<&Velocity, With<Player>>()
+        .iter(&app.world)
+        .next()
+        .unwrap();
+    (
+        Vec2::new(velocity.linear.x, velocity.linear.z).length(),
+        Vec3::from(velocity.angular).length(),
+    )
+}
+
+fn releasing_the_input_stops_the_tank(driving_mode: DrivingMode) {
+    let mut app = start_level_with(vec![distant_tower()], |app| {
+        app.insert_resource(driving_mode);
+    });
+
+    // turns and drives at the same time, in either mode
+    set_player_input(&mut app, Vec3::new(1.0, 0.0, 1.0), Vec3::X);
+    run_for(&mut app, 0.5);
+    let (speed, turn_rate) = player_motion(&mut app);
+    assert!(speed > 1.0 && turn_rate > 0.1);
+
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+    run_for(&mut app, 0.1);
+    let (speed, turn_rate) = player_motion(&mut app);
+    assert!(speed < 0.05);
+    assert!(turn_rate < 0.05);
+}
+
+#[test]
+fn releasing_the_input_stops_the_tank_in_world_relative_mode() {
+    releasing_the_input_stops_the_tank(DrivingMode::WorldRelative);
+}
+
+#[test]
+fn releasing_the_input_stops_the_tank_in_tank_relative_mode() {
+    releasing_the_input_stops_the_tank(DrivingMode::TankRelative);
+}
+
+fn physics_scale(app: &App) -> f32 {
+    app.world.get_resource::<PhysicsTime>().unwrap().scale()
+}
+
+fn push_state(app: &mut App, state: GameState) {
+    app.world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .push(state)
+        .unwrap();
+    app.update();
+}
+
+fn pop_state(app: &mut App) {
+    app.world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .pop()
+        .unwrap();
+    app.update();
+}
+
+#[test]
+fn menus_over_the_level_pause_physics() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+
+    push_state(&mut app, GameState::Controls);
+    assert_eq!(physics_scale(&app), 0.0);
+    pop_state(&mut app);
+    assert_eq!(physics_scale(&app), 1.0);
+
+    // backing out of the controls into the pause menu keeps the level paused
+    push_state(&mut app, GameState::Paused);
+    push_state(&mut app, GameState::Controls);
+    pop_state(&mut app);
+    assert_eq!(*current_state(&app), GameState::Paused);
+    assert_eq!(physics_scale(&app), 0.0);
+    pop_state(&mut app);
+    assert_eq!(physics_scale(&app), 1.0);
+}
+
+#[test]
+fn pause_key_pauses_and_resumes() {
+    let mut app = start_level(vec![distant_tower()]);
+
+    press(&mut app, Action::Pause);
+    app.update();
+    assert_eq!(*current_state(&app), GameState::Paused);
+    assert_eq!(physics_scale(&app), 0.0);
+
+    press(&mut app, Action::Pause);
+    app.update();
+    assert_eq!(*current_state(&app), GameState::Playing);
+    assert_eq!(physics_scale(&app), 1.0);
+}
+
+#[test]
+fn level_that_fails_to_load_goes_back_to_main_menu() {
+    let mut app = App::new();
+    app.insert_resource(SimulationTime::fixed(DELTA))
+        .add_plugins(MinimalPlugins)
+        .add_plugin(TransformPlugin)
+        .add_plugin(AssetPlugin)
+        .add_plugins(HeadlessPlugins);
+    app.update();
+
+    let level = app
+        .world
+        .get_resource::<AssetServer>()
+        .unwrap()
+        .load("levels/missing.level.ron");
+    app.world.get_resource_mut::<CurrentLevel>().unwrap().0 = level;
+    app.world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .set(GameState::Loading)
+        .unwrap();
+    app.update();
+
+    for _ in 0..1000 {
+        if *current_state(&app) != GameState::Loading {
+            break;
+        }
+        app.update();
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(*current_state(&app), GameState::MainMenu);
+}
+
+#[test]
+fn restarting_unfreezes_time() {
+    let mut app = start_level_with(vec![distant_tower()], |app| {
+        app.add_plugin(TimeControlPlugin);
+    });
+    press(&mut app, Action::SlowDownTime);
+    press(&mut app, Action::FreezeTime);
+    assert_eq!(physics_scale(&app), 0.0);
+
+    press(&mut app, Action::Restart);
+    run_for(&mut app, 1.0);
+    assert_eq!(*current_state(&app), GameState::Playing);
+    assert!(!app.world.get_resource::<TimeControl>().unwrap().frozen());
+    assert_eq!(physics_scale(&app), 1.0);
+}
+
+#[test]
+fn time_scale_slows_down_cooldown() {
+    let mut app = start_level(vec![distant_tower()]);
+    run_for(&mut app, 1.0);
+    set_player_input(&mut app, Vec3::ZERO, Vec3::X);
+
+    app.world
+        .get_resource_mut::<SimulationTime>()
+        .unwrap()
+        .set_scale(0.5);
+    app.world
+        .get_resource_mut::<PhysicsTime>()
+        .unwrap()
+        .set_scale(0.5);
+
+    fire(&mut app);
+    run_for(&mut app, SHOOT_COOLDOWN);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    run_for(&mut app, SHOOT_COOLDOWN);
+    fire(&mut app);
+    assert_eq!(player_bullet_count(&mut app), 2);
+}
+
+#[test]
+fn simulation_is_reproducible() {
+    let run = || {
+        let mut app = start_level(vec![tower(Vec3::new(0.0, 0.0, -30.0), 4)]);
+        // drives straight ahead, so the tower's lead is exact and its bullets hit squarely
+        set_player_input(&mut app, Vec3::new(-1.0, 0.0, 0.0), Vec3::X);
+        run_for(&mut app, 2.0);
+        fire(&mut app);
+        run_for(&mut app, 2.0);
+
+        app.world
+            .query_filtered::<&Transform, With<Player>>()
+            .iter(&app.world)
+            .next()
+            .unwrap()
+            .translation
+    };
+
+    let first = run();
+    let second = run();
+
+    assert!(first.distance(Vec3::new(0.0, 3.0, 0.0)) > 1.0);
+    assert_eq!(first, second);
+}
+
+/// Ticks that `script_movement` has run for.
+#[derive(Default)]
+struct Ticks(u32);
+
+/// Drives into the tower's line of fire, and stops there half way through to get hit.
+fn script_movement(mut ticks: ResMut<Ticks>, mut query: Query<&mut MovementInputDirection>) {
+    query.single_mut().0 = if ticks.0 < 120 {
+        Vec3::new(1.0, 0.0, 1.0)
+    } else {
+        Vec3::ZERO
+    };
+    ticks.0 += 1;
+}
+
+#[test]
+fn simulation_is_independent_of_frame_rate() {
+    let run = |frame_delta: f32| {
+        let mut app = start_level_with(vec![tower(Vec3::new(0.0, 0.0, -30.0), 4)], |app| {
+            // the input is picked by the tick, so that every tick gets the same input at any
+            // frame rate
+            app.insert_resource(SimulationTime::fixed(DELTA).with_frame_delta(frame_delta))
+                .init_resource::<Ticks>()
+                .add_system_set_to_stage(
+                    SimulationStage,
+                    SystemSet::on_update(GameState::Playing)
+                        .with_system(script_movement.before(PlayerSystem::Simulate)),
+                );
+        });
+
+        // however many ticks run per update, the level runs for the same number of them
+        let ticks = |app: &App| app.world.get_resource::<Ticks>().unwrap().0;
+        while ticks(&app) < 240 {
+            app.update();
+        }
+        assert_eq!(ticks(&app), 240);
+
+        let position = app
+            .world
+            .query_filtered::<&Transform, With<Player>>()
+            .iter(&app.world)
+            .next()
+            .unwrap()
+            .translation;
+        (
+            position,
+            player(&mut app).health,
+            tower_bullet_count(&mut app),
+        )
+    };
+
+    let slow = run(DELTA * 2.0);
+    let fast = run(DELTA / 2.0);
+
+    assert!(slow.1 < 10);
+    assert_eq!(slow, fast);
+}
+
+#[test]
+fn rebinding_a_taken_binding_swaps_it() {
+    let mut bindings = InputBindings::default();
+    let fire = bindings.get(Action::Fire);
+
+    bindings.set(Action::Fire, Binding::Key(KeyCode::W));
+
+    assert_eq!(bindings.get(Action::Fire), Binding::Key(KeyCode::W));
+    assert_eq!(bindings.get(Action::MoveForward), fire);
+}
+
+#[test]
+fn replay_plays_back_recorded_input() {
+    let towers = || vec![tower(Vec3::new(0.0, 0.0, -30.0), 4)];
+    let player_position = |app: &mut App| {
+        app.world
+            .query_filtered::<&Transform, With<Player>>()
+            .iter(&app.world)
+            .next()
+            .unwrap()
+            .translation
+    };
+
+    let mut app = start_level_with(towers(), |app| {
+        app.add_plugin(ReplayPlugin::default())
+            .add_plugin(TimeControlPlugin)
+            .insert_resource(DrivingMode::TankRelative);
+    });
+    set_player_input(&mut app, Vec3::new(1.0, 0.0, 1.0), Vec3::X);
+    run_for(&mut app, 1.0);
+    fire(&mut app);
+    press(&mut app, Action::SlowDownTime);
+    set_player_input(&mut app, Vec3::new(0.0, 0.0, -1.0), Vec3::Z);
+    run_for(&mut app, 1.0);
+
+    let recorded_position = player_position(&mut app);
+    let replay = app
+        .world
+        .get_resource::<ReplayRecorder>()
+        .unwrap()
+        .replay()
+        .clone();
+    let ticks = replay.inputs().count();
+
+    let mut app = start_level_with(towers(), |app| {
+        app.add_plugin(ReplayPlugin::default())
+            .insert_resource(ReplayPlayback::new(replay));
+    });
+    // the first tick already ran when the level started
+    (1..ticks).for_each(|_| app.update());
+
+    assert_eq!(player_position(&mut app), recorded_position);
+    assert_eq!(player_bullet_count(&mut app), 1);
+
+    // the replay's driving mode only lasts while it plays
+    app.world
+        .get_resource_mut::<State<GameState>>()
+        .unwrap()
+        .set(GameState::MainMenu)
+        .unwrap();
+    app.update();
+    assert_eq!(
+        *app.world.get_resource::<DrivingMode>().unwrap(),
+        DrivingMode::WorldRelative
+    );
+}